@@ -0,0 +1,308 @@
+use crate::{
+    package::{self, KernelSource},
+    parse_conf::Config,
+    runner::{cmd, Executor},
+    sail::MICROCODE_PACKAGES,
+};
+use anyhow::Result;
+
+/// Database of the repository, pacman.conf names it `[sail]`
+pub const REPO_DB: &str = "sail.db";
+/// Public key the packages and the database are signed with
+pub const REPO_KEY: &str = "sail.gpg";
+pub const REPO_KEYID: &str = "sail.keyid";
+pub const ARCHZFS_KEY: &str = "archzfs.gpg";
+pub const ARCHZFS_KEYID: &str = "archzfs.keyid";
+pub const ARCHZFS_MIRRORLIST: &str = "mirrorlist-archzfs";
+
+/// Files `start` needs in the repository besides the packages
+pub const REPO_FILES: [&str; 6] = [
+    REPO_DB,
+    REPO_KEY,
+    REPO_KEYID,
+    ARCHZFS_KEY,
+    ARCHZFS_KEYID,
+    ARCHZFS_MIRRORLIST,
+];
+
+/// AUR packages installed by `install_aurs`, prebuilt in the repository
+pub const AUR_PACKAGES: [&str; 4] = ["paru-bin", "bieaz", "rozb3-pac", "zrepl-bin"];
+
+/// Empty local database, so pacman downloads every dependency and not only
+/// the ones missing on this machine
+const DBPATH: &str = "/tmp/sail-cache-db";
+/// pacman.conf of the host with the archzfs repository, which a stock host
+/// lacks, and a copy of its keyring trusting the archzfs key
+const PACMAN_CONF: &str = "/tmp/sail-cache-pacman.conf";
+const GPGDIR: &str = "/tmp/sail-cache-pacman-gnupg";
+const BUILD_DIR: &str = "/tmp/sail-cache-build";
+/// Keyring of the signing key, removed after the build so the private key
+/// never sits next to the packages
+const GNUPGHOME: &str = "/tmp/sail-cache-gnupg";
+
+/// Dependencies of the x86_64 package of a .SRCINFO, e.g. "sh>=5"
+fn srcinfo_depends(srcinfo: &str) -> Vec<String> {
+    srcinfo
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            matches!(key.trim(), "depends" | "depends_x86_64").then(|| value.trim().to_owned())
+        })
+        .collect()
+}
+
+/// Download every package `start` installs with `conf`, with the microcode
+/// of both vendors, build the AUR ones and index them in `dir`, with the
+/// archzfs key and mirrorlist. Packages already in `dir` are kept, so a
+/// pacman cache can be turned into a repository too. pacman checks the
+/// downloaded packages, then every package and the database are signed
+/// with a new key of the repository, as the AUR ones have no signature.
+pub fn build(conf: &Config, dir: &str, ex: &mut dyn Executor) -> Result<()> {
+    let linux = conf.linvar.get_name();
+    let linux_headers = linux.to_owned() + "-headers";
+    let zfs = conf.zfs_type.get_package(linux);
+    let sync: Vec<String> = [
+        "--config",
+        PACMAN_CONF,
+        "--gpgdir",
+        GPGDIR,
+        "--dbpath",
+        DBPATH,
+    ]
+    .map(str::to_owned)
+    .to_vec();
+
+    ex.log("Create the repository directory");
+    ex.run(&cmd!(%"mkdir -p", dir, DBPATH))?;
+
+    ex.log("Download the archzfs key and mirrorlist");
+    let file = |name: &str| format!("{}/{}", dir, name);
+    ex.run(&cmd!(%"curl -fL -o", file(ARCHZFS_KEY), "https://archzfs.com/archzfs.gpg"))?;
+    ex.run(&cmd!(%"curl -fL -o", file(ARCHZFS_KEYID), "https://git.io/JsfVS"))?;
+    ex.run(&cmd!(%"curl -fL -o", file(ARCHZFS_MIRRORLIST), "https://git.io/Jsfw2"))?;
+
+    ex.log("Add the archzfs repository");
+    ex.run(&cmd!(%"cp /etc/pacman.conf", PACMAN_CONF))?;
+    let repositories = ex.query(&cmd!(%"pacman-conf --repo-list"))?;
+    if !repositories
+        .lines()
+        .any(|repository| repository == "archzfs")
+    {
+        let archzfs = format!("\n[archzfs]\nInclude = {}", file(ARCHZFS_MIRRORLIST));
+        ex.writeln_a(&archzfs, PACMAN_CONF)?;
+    }
+    ex.run(&cmd!(%"rm -rf", GPGDIR))?;
+    ex.run(&cmd!(%"cp -a /etc/pacman.d/gnupg", GPGDIR))?;
+    ex.run(&cmd!(%"pacman-key --gpgdir", GPGDIR, "-a", file(ARCHZFS_KEY)))?;
+    let lsign = r#"pacman-key --gpgdir "$1" --lsign-key "$(cat "$2")""#;
+    ex.run(&cmd!(%"bash -c", lsign, "bash", GPGDIR, file(ARCHZFS_KEYID)))?;
+
+    ex.log("Update pacman repository");
+    ex.run(&cmd!(%"pacman -Sy", &sync[..]))?;
+
+    ex.log("Check kernel version compatible with zfs");
+    let kernels = package::find(ex, linux, &sync)?;
+    let zfs_packages = package::find(ex, &zfs, &sync)?;
+    let mut options = sync.clone();
+    // The CPU of the machine installed to isn't known here
+    let packages = conf.packages.select(&MICROCODE_PACKAGES)?;
+    let kernel = package::pick_kernel(&kernels, &zfs_packages)?;
+    let zfs_repository = kernel.get_zfs_repository();
+    let mut targets: Vec<String> = [
+        packages.get_base(),
+        packages.get_firmware(),
+        packages.get_extra(),
+        &[
+            format!("{}/{}", zfs_repository, zfs),
+            format!("{}/zfs-utils", zfs_repository),
+        ],
+    ]
+    .concat();
+    match kernel {
+        KernelSource::Repo { repository, .. } => targets.extend([
+            format!("{}/{}", repository, linux),
+            format!("{}/{}", repository, linux_headers),
+        ]),
+        KernelSource::Archive { version, .. } => {
+            for name in [linux, &linux_headers] {
+                package::fetch_archived(ex, name, &version, dir)?;
+                // Keeps pacman from looking for it in the sync databases
+                options.push("--assume-installed".to_owned());
+                options.push(format!("{}={}", name, version));
+            }
+        }
+    }
+
+    ex.log("Build the AUR packages");
+    ex.run(&cmd!(%"install -d -o nobody", BUILD_DIR))?;
+    // The home of nobody is /, which it can't write to
+    let nobody = [
+        "runuser".to_owned(),
+        "-u".to_owned(),
+        "nobody".to_owned(),
+        "--".to_owned(),
+        "env".to_owned(),
+        format!("HOME={}", BUILD_DIR),
+        format!("BUILDDIR={}/makepkg", BUILD_DIR),
+    ];
+    for name in AUR_PACKAGES {
+        let src = format!("{}/{}", BUILD_DIR, name);
+        let url = format!("https://aur.archlinux.org/{}.git", name);
+        ex.run(&cmd!(&nobody[..], %"git clone", url, &src))?;
+        // Their dependencies are downloaded below instead of installed here
+        let makepkg = format!("cd {} && makepkg -d --noconfirm", src);
+        ex.run(&cmd!(&nobody[..], %"bash -c", makepkg))?;
+        ex.run(&cmd!(%"bash -c", r#"cp "$1"/*.pkg.tar.zst "$2""#, "bash", &src, dir))?;
+
+        let srcinfo = ex.output(&cmd!("cat", format!("{}/.SRCINFO", src)))?;
+        for depend in srcinfo_depends(&srcinfo) {
+            let depend_name = depend.split(['<', '>', '=']).next().unwrap_or_default();
+            if !AUR_PACKAGES.contains(&depend_name) && !targets.contains(&depend) {
+                targets.push(depend);
+            }
+        }
+    }
+
+    ex.log("Download the packages");
+    ex.run(&cmd!(%"pacman -Sw --noconfirm --cachedir", dir, options, targets))?;
+
+    ex.log("Sign the packages with a new key of the repository");
+    let gpg = ["gpg", "--batch", "--yes", "--homedir", GNUPGHOME];
+    // A keyring left by an interrupted build would hold another key
+    ex.run(&cmd!(%"rm -rf", GNUPGHOME))?;
+    ex.run(&cmd!(%"install -d -m 700", GNUPGHOME))?;
+    ex.run(
+        &cmd!(gpg, "--passphrase", "", "--quick-gen-key", "sail repository", %"ed25519 sign never"),
+    )?;
+    let keyid = r#"gpg --batch --homedir "$1" --with-colons --list-secret-keys | awk -F: '$1 == "fpr" { print $10; exit }' > "$2""#;
+    ex.run(&cmd!(%"bash -c", keyid, "bash", GNUPGHOME, file(REPO_KEYID)))?;
+    ex.run(&cmd!(
+        gpg,
+        "--armor",
+        "--output",
+        file(REPO_KEY),
+        "--export"
+    ))?;
+    let sign = r#"for pkg in "$2"/*.pkg.tar.zst; do gpg --batch --yes --homedir "$1" --detach-sign "$pkg" || exit; done"#;
+    ex.run(&cmd!(%"bash -c", sign, "bash", GNUPGHOME, dir))?;
+
+    ex.log("Index the packages");
+    let db = file(&format!("{}.tar.gz", REPO_DB));
+    // Every package again, their entries carry the new signatures
+    let index = r#"GNUPGHOME="$3" repo-add -q -s -k "$(cat "$4")" "$1" "$2"/*.pkg.tar.zst"#;
+    ex.run(&cmd!(%"bash -c", index, "bash", db, dir, GNUPGHOME, file(REPO_KEYID)))?;
+
+    ex.log("Clean up");
+    for homedir in [GNUPGHOME, GPGDIR] {
+        ex.run(&cmd!(%"gpgconf --homedir", homedir, %"--kill gpg-agent"))?;
+    }
+    ex.run(&cmd!(%"rm -rf", DBPATH, BUILD_DIR, GNUPGHOME, GPGDIR, PACMAN_CONF))?;
+
+    eprintln!(
+        "\nRepository ready, install from it with this in sail.toml:\n[repo]\npath = \"{}\"",
+        dir
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{Event, Recorder};
+
+    #[test]
+    fn srcinfo_dependencies() {
+        let srcinfo = "pkgbase = rozb3-pac
+\tpkgdesc = Pacman hook for bieaz
+\tpkgver = 0.3.2
+\tarch = any
+\tmakedepends = git
+\tdepends = bieaz
+\tdepends = pacman>=6
+
+pkgname = rozb3-pac
+";
+        assert_eq!(srcinfo_depends(srcinfo), ["bieaz", "pacman>=6"]);
+    }
+
+    #[test]
+    fn build_downloads_packages_of_the_config() {
+        let linux = "Repository : core\nName : linux\nVersion : 6.6.1.arch1-1\n";
+        let zfs = "Repository : archzfs\nName : zfs-linux\nVersion : 2.2.0_6.6.1.arch1.1-1\n\
+                   Depends On : kmod  zfs-utils=2.2.0  linux=6.6.1.arch1-1\n";
+        let si = [
+            "env",
+            "LC_ALL=C",
+            "pacman",
+            "-Si",
+            "--config",
+            PACMAN_CONF,
+            "--gpgdir",
+            GPGDIR,
+            "--dbpath",
+            DBPATH,
+        ];
+        let mut rec = Recorder::default()
+            .with_output(&[&si[..], &["linux"]].concat(), linux)
+            .with_output(&[&si[..], &["zfs-linux"]].concat(), zfs)
+            .with_output(
+                &["cat", "/tmp/sail-cache-build/rozb3-pac/.SRCINFO"],
+                "\tdepends = bieaz\n\tdepends = pacman>=6\n",
+            );
+        build(&Config::default(), "/srv/repo", &mut rec).unwrap();
+
+        let download = &rec.argvs_of(&["pacman", "-Sw"])[0];
+        for name in [
+            "base",
+            "archzfs/zfs-linux",
+            "core/linux-headers",
+            "pacman>=6",
+        ] {
+            assert!(download.contains(&name), "{} isn't downloaded", name);
+        }
+        // Built from the AUR instead
+        assert!(!download.contains(&"bieaz"));
+
+        // A stock host has no archzfs repository
+        assert!(rec.events.contains(&Event::Append(
+            PACMAN_CONF.to_owned(),
+            "\n[archzfs]\nInclude = /srv/repo/mirrorlist-archzfs".to_owned()
+        )));
+        assert!(
+            rec.argvs_of(&["pacman", "-Sy", "--config", PACMAN_CONF])
+                .len()
+                == 1
+        );
+
+        let clones = rec.argvs_of(&["runuser", "-u", "nobody", "--", "env"]);
+        assert_eq!(clones.len(), AUR_PACKAGES.len() * 2);
+        assert!(clones
+            .iter()
+            .all(|argv| argv[5] == "HOME=/tmp/sail-cache-build"));
+
+        let index = rec
+            .argvs()
+            .into_iter()
+            .find(|argv| argv.len() > 2 && argv[2].contains("repo-add -q -s"))
+            .unwrap();
+        assert_eq!(
+            index[4..],
+            [
+                "/srv/repo/sail.db.tar.gz",
+                "/srv/repo",
+                GNUPGHOME,
+                "/srv/repo/sail.keyid"
+            ]
+        );
+        let export =
+            &rec.argvs_of(&["gpg", "--batch", "--yes", "--homedir", GNUPGHOME, "--armor"])[0];
+        assert_eq!(export[7], "/srv/repo/sail.gpg");
+        // The private key goes away
+        assert!(rec
+            .argvs_of(&["rm", "-rf"])
+            .last()
+            .unwrap()
+            .contains(&GNUPGHOME));
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use std::{fs, path::Path};
+
+const BY_ID: &str = "/dev/disk/by-id";
+
+/// Stable /dev/disk/by-id name of `disk`, which can be any path of the
+/// disk, e.g. /dev/sda or /dev/disk/by-path/... The kernel name is kept
+/// when the disk has no by-id link, like loop devices.
+pub fn resolve_by_id(disk: &str) -> Result<String> {
+    let dev = fs::canonicalize(disk).with_context(|| format!("Resolving {}", disk))?;
+
+    let mut names = Vec::new();
+    if Path::new(BY_ID).is_dir() {
+        for entry in fs::read_dir(BY_ID)? {
+            let entry = entry?;
+            if fs::canonicalize(entry.path()).ok().as_ref() == Some(&dev) {
+                if let Ok(name) = entry.file_name().into_string() {
+                    names.push(name);
+                }
+            }
+        }
+    }
+
+    let path = match pick_by_id(names) {
+        Some(name) => format!("{}/{}", BY_ID, name),
+        None => dev.to_string_lossy().into_owned(),
+    };
+
+    Ok(path)
+}
+
+/// Prefer the names showing the model and serial over the wwn and eui
+/// ones, then the shortest, e.g. without the namespace suffix of nvme
+fn pick_by_id(mut names: Vec<String>) -> Option<String> {
+    let is_opaque = |name: &str| {
+        ["wwn-", "nvme-eui.", "nvme-nvme."]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    };
+    names.sort_by_key(|name| (is_opaque(name), name.len(), name.clone()));
+
+    names.into_iter().next()
+}
+
+/// Device path of partition `partnum` of `disk`
+pub fn partition_path(disk: &str, partnum: usize) -> String {
+    if disk.starts_with("/dev/disk/by-") {
+        format!("{}-part{}", disk, partnum)
+    } else if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        // nvme0n1, mmcblk0, loop0
+        format!("{}p{}", disk, partnum)
+    } else {
+        format!("{}{}", disk, partnum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partition_paths() {
+        assert_eq!(partition_path("/dev/sda", 1), "/dev/sda1");
+        assert_eq!(partition_path("/dev/vdb", 12), "/dev/vdb12");
+        assert_eq!(partition_path("/dev/nvme0n1", 2), "/dev/nvme0n1p2");
+        assert_eq!(partition_path("/dev/mmcblk0", 3), "/dev/mmcblk0p3");
+        assert_eq!(partition_path("/dev/loop0", 1), "/dev/loop0p1");
+        assert_eq!(
+            partition_path("/dev/disk/by-id/ata-WDC_WD5000AAKX_WD-WCAYU1", 3),
+            "/dev/disk/by-id/ata-WDC_WD5000AAKX_WD-WCAYU1-part3"
+        );
+        assert_eq!(
+            partition_path("/dev/disk/by-path/pci-0000:00:1f.2-ata-1", 1),
+            "/dev/disk/by-path/pci-0000:00:1f.2-ata-1-part1"
+        );
+    }
+
+    #[test]
+    fn readable_by_id_names_first() {
+        let names = vec![
+            "nvme-eui.0025385b71b07e2f".to_owned(),
+            "nvme-Samsung_SSD_970_EVO_Plus_500GB_S4EVNX0N123456_1".to_owned(),
+            "nvme-Samsung_SSD_970_EVO_Plus_500GB_S4EVNX0N123456".to_owned(),
+        ];
+        assert_eq!(
+            pick_by_id(names).unwrap(),
+            "nvme-Samsung_SSD_970_EVO_Plus_500GB_S4EVNX0N123456"
+        );
+
+        let names = vec![
+            "wwn-0x50014ee2b5a1c2d3".to_owned(),
+            "ata-WDC_WD5000AAKX-08U6AA0_WD-WCC2E1234567".to_owned(),
+        ];
+        assert_eq!(
+            pick_by_id(names).unwrap(),
+            "ata-WDC_WD5000AAKX-08U6AA0_WD-WCC2E1234567"
+        );
+
+        assert_eq!(
+            pick_by_id(vec!["wwn-0x5000c500a1b2c3d4".to_owned()]).unwrap(),
+            "wwn-0x5000c500a1b2c3d4"
+        );
+        assert_eq!(pick_by_id(Vec::new()), None);
+    }
+}
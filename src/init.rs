@@ -0,0 +1,363 @@
+use crate::{
+    device,
+    parse_conf::{self, Config},
+    sail::{self, LinuxVariant, StorageType, Topology, User, ZfsType},
+    size,
+};
+use anyhow::{bail, Context, Result};
+use cradle::{output::StdoutTrimmed, run_result};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Disk listed by lsblk
+#[derive(Debug, PartialEq)]
+struct BlockDevice {
+    path: String,
+    size: u64,
+    model: String,
+    rotational: bool,
+}
+
+/// Disks of `lsblk -dnpb -P -o NAME,SIZE,MODEL,ROTA,TYPE`, without
+/// partitions, loop devices and zram
+fn parse_lsblk(out: &str) -> Vec<BlockDevice> {
+    let mut devices = Vec::new();
+
+    for line in out.lines() {
+        let mut fields = Vec::new();
+        let mut rest = line.trim();
+        while let Some((key, value)) = rest.split_once("=\"") {
+            let Some((value, after)) = value.split_once('"') else {
+                break;
+            };
+            fields.push((key.trim(), value.replace(r"\x20", " ")));
+            rest = after;
+        }
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map_or("", |(_, value)| value.as_str())
+        };
+
+        if field("TYPE") != "disk" || field("NAME").starts_with("/dev/zram") {
+            continue;
+        }
+        devices.push(BlockDevice {
+            path: field("NAME").to_owned(),
+            size: field("SIZE").parse().unwrap_or(0),
+            model: field("MODEL").trim().to_owned(),
+            rotational: field("ROTA") == "1",
+        });
+    }
+
+    devices
+}
+
+/// Indexes of the space or comma separated numbers of `answer`, counted
+/// from 1 up to `count`
+fn parse_selection(answer: &str, count: usize) -> Result<Vec<usize>> {
+    let mut indexes = Vec::new();
+    for number in answer.split(|c: char| c == ',' || c.is_whitespace()) {
+        if number.is_empty() {
+            continue;
+        }
+        let index = match number.parse::<usize>() {
+            Ok(n) if (1..=count).contains(&n) => n - 1,
+            _ => bail!(r#""{}" isn't a number between 1 and {}"#, number, count),
+        };
+        if indexes.contains(&index) {
+            bail!("{} is chosen more than once", number);
+        }
+        indexes.push(index);
+    }
+    if indexes.is_empty() {
+        bail!("Choose at least one");
+    }
+
+    Ok(indexes)
+}
+
+/// Line typed after `question`, `default` when it's empty
+fn ask(question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        eprint!("{}: ", question);
+    } else {
+        eprint!("{} [{}]: ", question, default);
+    }
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        bail!("No answer to \"{}\"", question);
+    }
+    let answer = answer.trim();
+
+    Ok(if answer.is_empty() { default } else { answer }.to_owned())
+}
+
+/// Ask again until `parse` accepts the answer
+fn ask_valid<T>(question: &str, default: &str, parse: impl Fn(&str) -> Result<T>) -> Result<T> {
+    loop {
+        match parse(&ask(question, default)?) {
+            Ok(value) => return Ok(value),
+            Err(err) => eprintln!("{}, try again", err),
+        }
+    }
+}
+
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    let default = if default { "y" } else { "n" };
+
+    ask_valid(question, default, |answer| match answer {
+        "y" | "Y" | "yes" => Ok(true),
+        "n" | "N" | "no" => Ok(false),
+        _ => bail!(r#""{}" isn't y or n"#, answer),
+    })
+}
+
+/// Index of the chosen one of `options`
+fn choose(question: &str, options: &[String], default: usize) -> Result<usize> {
+    eprintln!("\n{}:", question);
+    for (i, option) in options.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, option);
+    }
+
+    ask_valid(
+        "Number",
+        &(default + 1).to_string(),
+        |answer| match parse_selection(answer, options.len())?[..] {
+            [index] => Ok(index),
+            _ => bail!("Choose only one"),
+        },
+    )
+}
+
+/// Timezone of the live system, e.g. Europe/Berlin
+fn current_timezone() -> Option<String> {
+    let target = fs::read_link("/etc/localtime").ok()?;
+    let (_, timezone) = target.to_str()?.split_once("zoneinfo/")?;
+
+    Some(timezone.to_owned())
+}
+
+fn prompt_user_password(name: &str) -> Result<Option<String>> {
+    loop {
+        let prompt = format!("Password of {} (empty to lock the account): ", name);
+        let password = rpassword::prompt_password(prompt)?;
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        let confirmation = rpassword::prompt_password(format!("Retype password of {}: ", name))?;
+        if password != confirmation {
+            eprintln!("Passwords don't match, try again");
+            continue;
+        }
+
+        let hash = pwhash::sha512_crypt::hash(password)
+            .with_context(|| format!("Hashing password of {}", name))?;
+        return Ok(Some(hash));
+    }
+}
+
+fn ask_disks(conf: &mut Config) -> Result<()> {
+    let StdoutTrimmed(out) = run_result!(%"lsblk -dnpb -P -o NAME,SIZE,MODEL,ROTA,TYPE")?;
+    let devices = parse_lsblk(&out);
+    if devices.is_empty() {
+        bail!("No disks found");
+    }
+
+    eprintln!("\nDisks:");
+    for (i, device) in devices.iter().enumerate() {
+        let kind = if device.rotational { "HDD" } else { "SSD" };
+        eprintln!(
+            "  {}) {} {} {} {}",
+            i + 1,
+            device.path,
+            size::human(device.size),
+            kind,
+            device.model
+        );
+    }
+    let chosen = ask_valid("Disks to install to, e.g. 1 or 1 2", "", |answer| {
+        parse_selection(answer, devices.len())
+    })?;
+
+    let mut disks = Vec::new();
+    for &i in &chosen {
+        disks.push(device::resolve_by_id(&devices[i].path)?);
+    }
+    conf.storage_type = if chosen.iter().any(|&i| devices[i].rotational) {
+        StorageType::Hdd
+    } else {
+        StorageType::Ssd
+    };
+
+    if let [disk] = &disks[..] {
+        conf.disk = disk.clone();
+    } else {
+        let topologies: Vec<Topology> = [
+            Topology::Mirror,
+            Topology::Raidz1,
+            Topology::Raidz2,
+            Topology::Raidz3,
+        ]
+        .into_iter()
+        .filter(|topology| topology.min_disks() <= disks.len())
+        .collect();
+        let options: Vec<String> = topologies
+            .iter()
+            .map(|topology| format!("{:?}", topology))
+            .collect();
+        let i = choose("Layout of the pools over the disks", &options, 0)?;
+        conf.topology = topologies.into_iter().nth(i).context("No topology")?;
+        conf.disks = disks;
+    }
+
+    conf.wipe = confirm(
+        "Erase everything on the disks instead of adding partitions? (y/n)",
+        false,
+    )?;
+
+    Ok(())
+}
+
+fn ask_kernel(conf: &mut Config) -> Result<()> {
+    let variants = [
+        ("linux", LinuxVariant::Linux),
+        ("linux-lts", LinuxVariant::LinuxLts),
+        ("linux-zen", LinuxVariant::LinuxZen),
+        ("linux-hardened", LinuxVariant::LinuxHardened),
+    ];
+    let options: Vec<String> = variants.iter().map(|(name, _)| name.to_string()).collect();
+    let i = choose("Kernel", &options, 0)?;
+    conf.linvar = variants.into_iter().nth(i).context("No kernel")?.1;
+
+    let options = [
+        "zfs-linux* built for the kernel".to_owned(),
+        "zfs-dkms, built on every kernel update".to_owned(),
+    ];
+    conf.zfs_type = match choose("ZFS module", &options, 0)? {
+        0 => ZfsType::Normal,
+        _ => ZfsType::Dkms,
+    };
+
+    Ok(())
+}
+
+fn ask_system(conf: &mut Config) -> Result<()> {
+    eprintln!();
+    conf.hostname = ask_valid("Hostname", &conf.hostname, |hostname| {
+        sail::check_hostname(hostname)?;
+        Ok(hostname.to_owned())
+    })?;
+
+    let timezone = current_timezone().unwrap_or_else(|| conf.timezone.clone());
+    conf.timezone = ask_valid("Timezone", &timezone, |timezone| {
+        sail::check_timezone(timezone)?;
+        Ok(timezone.to_owned())
+    })?;
+
+    Ok(())
+}
+
+fn ask_users(conf: &mut Config) -> Result<()> {
+    loop {
+        eprintln!();
+        let name = ask_valid("User name (empty when done)", "", |name| {
+            if !name.is_empty() {
+                let mut users = conf.users.clone();
+                users.push(User {
+                    name: name.to_owned(),
+                    ..User::default()
+                });
+                sail::check_users(&users)?;
+            }
+            Ok(name.to_owned())
+        })?;
+        if name.is_empty() {
+            return Ok(());
+        }
+
+        let sudo = confirm("Allow sudo? (y/n)", true)?;
+        let password_hash = prompt_user_password(&name)?;
+        conf.users.push(User {
+            name,
+            sudo,
+            password_hash,
+            ..User::default()
+        });
+    }
+}
+
+/// Write a commented sail.toml, filled in from questions when
+/// `interactive`
+pub fn init(interactive: bool, force: bool) -> Result<()> {
+    let conf_path = Path::new("sail.toml");
+    if conf_path.exists() && !force {
+        bail!("./sail.toml already exists, use --force to overwrite it");
+    }
+
+    let mut conf = Config::default();
+    if interactive {
+        ask_disks(&mut conf)?;
+        ask_kernel(&mut conf)?;
+        ask_system(&mut conf)?;
+        ask_users(&mut conf)?;
+    }
+
+    fs::write(conf_path, parse_conf::render(&conf))?;
+    eprintln!("\nWrote ./sail.toml, review it then run `sail start --dry-run`");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsblk_disks() {
+        let out = r#"NAME="/dev/sda" SIZE="500107862016" MODEL="WDC WD5000AAKX-0" ROTA="1" TYPE="disk"
+NAME="/dev/loop0" SIZE="10737418240" MODEL="" ROTA="0" TYPE="loop"
+NAME="/dev/nvme0n1" SIZE="500107862016" MODEL="Samsung SSD 970 EVO Plus 500GB" ROTA="0" TYPE="disk"
+NAME="/dev/zram0" SIZE="4294967296" MODEL="" ROTA="0" TYPE="disk"
+NAME="/dev/sr0" SIZE="1073741312" MODEL="QEMU DVD-ROM" ROTA="1" TYPE="rom""#;
+
+        assert_eq!(
+            parse_lsblk(out),
+            vec![
+                BlockDevice {
+                    path: "/dev/sda".to_owned(),
+                    size: 500107862016,
+                    model: "WDC WD5000AAKX-0".to_owned(),
+                    rotational: true,
+                },
+                BlockDevice {
+                    path: "/dev/nvme0n1".to_owned(),
+                    size: 500107862016,
+                    model: "Samsung SSD 970 EVO Plus 500GB".to_owned(),
+                    rotational: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn selections() {
+        assert_eq!(parse_selection("2", 3).unwrap(), vec![1]);
+        assert_eq!(parse_selection(" 1 3", 3).unwrap(), vec![0, 2]);
+        assert_eq!(parse_selection("3,1", 3).unwrap(), vec![2, 0]);
+        for answer in ["", "0", "4", "a", "1 1", "-1"] {
+            assert!(
+                parse_selection(answer, 3).is_err(),
+                "{} was accepted",
+                answer
+            );
+        }
+    }
+}
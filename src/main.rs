@@ -1,38 +1,206 @@
+mod cache;
+mod device;
+mod init;
+mod package;
 mod parse_args;
 mod parse_conf;
+mod partition_table;
+mod post_scripts;
+mod runner;
 mod sail;
 mod setup;
+mod size;
+mod state;
 mod string_res;
 
 use crate::sail::Sail;
 use anyhow::Result;
-use parse_args::SailState;
+use parse_args::{Output, SailState};
+use runner::{DryRun, Executor, Json, Real};
 use sail::{StorageType, ZfsType};
+use state::{InstallState, Journal};
 
-fn start(sail: Sail) -> Result<()> {
-    setup::check_as_root()?;
-    setup::init_check()?;
-    setup::partition_disk(&sail)?;
-    setup::format_disk(&sail)?;
-    setup::pacstrap(&sail)?;
-    setup::system_configuration(&sail)?;
-    setup::install_aurs()?;
-    setup::workarounds()?;
-    setup::bootloaders(&sail)?;
-    setup::finishing(&sail)?;
-    setup::post_scripts_gen()?;
-    setup::shot_and_clean()?;
+type Step = fn(&Sail, &mut dyn Executor) -> Result<()>;
+
+/// Step installing the system, with --keep-installed a later failure keeps
+/// it instead of rolling back
+const INSTALLED_STEP: &str = "pacstrap";
+
+/// Installation steps in order, the names are recorded in the install state
+const STEPS: [(&str, Step); 10] = [
+    ("partition_disk", setup::partition_disk),
+    ("format_disk", setup::format_disk),
+    ("pacstrap", setup::pacstrap),
+    ("system_configuration", setup::system_configuration),
+    ("install_aurs", setup::install_aurs),
+    ("workarounds", setup::workarounds),
+    ("bootloaders", setup::bootloaders),
+    ("finishing", setup::finishing),
+    ("post_scripts_gen", |_, ex| setup::post_scripts_gen(ex)),
+    ("shot_and_clean", setup::shot_and_clean),
+];
+
+fn start(
+    mut sail: Sail,
+    resumed: Option<InstallState>,
+    dry_run: bool,
+    keep_on_failure: bool,
+    keep_installed: bool,
+    output: Output,
+) -> Result<()> {
+    let mut real;
+    let mut json;
+    let resume = resumed.is_some();
+    let ex: &mut dyn Executor = match output {
+        Output::Text if dry_run => &mut DryRun,
+        Output::Json if dry_run => {
+            json = Json::dry_run();
+            &mut json
+        }
+        Output::Text => {
+            real = Real::with_log(resume)?;
+            &mut real
+        }
+        Output::Json => {
+            json = Json::new(Real::with_log(resume)?);
+            &mut json
+        }
+    };
+    if !dry_run {
+        setup::check_as_root()?;
+        setup::init_check(&sail, ex)?;
+    }
+
+    sail.prompt_passphrase(dry_run)?;
+
+    let mut state = match resumed {
+        Some(state) => {
+            if state.is_done("format_disk") {
+                setup::remount(&sail, ex)?;
+            }
+            state
+        }
+        None => {
+            // A dry run doesn't touch the state of an unfinished installation
+            if !dry_run {
+                InstallState::check_unfinished()?;
+            }
+            InstallState::new(sail.get_layout())
+        }
+    };
+
+    if sail.is_wiping() && !dry_run && !state.is_done("partition_disk") {
+        setup::confirm_wipe(&sail, ex)?;
+    }
+
+    if !state.is_done("system_configuration") {
+        sail.prompt_root_password(dry_run)?;
+    }
+
+    for (name, step) in STEPS {
+        if state.is_done(name) {
+            eprintln!("Skipping {}, already done", name);
+            continue;
+        }
+
+        if state.undo_step(name, ex)? && !dry_run {
+            state.save()?;
+        }
+
+        ex.begin_step(name);
+        let result = step(&sail, &mut Journal::new(ex, &mut state, name));
+        ex.end_step(name, result.as_ref().err());
+        if let Err(err) = result {
+            if dry_run {
+                return Err(err);
+            }
+
+            if keep_on_failure {
+                state.save()?;
+                eprintln!(
+                    "\n{} failed, keeping the changes, continue with `sail start --resume`\n",
+                    name
+                );
+            } else if keep_installed && state.is_done(INSTALLED_STEP) {
+                state.save()?;
+                eprintln!(
+                    "\n{} failed after the system was installed, keeping it instead of rolling back\n\
+                     Fix the problem, then continue with `sail start --resume`\n",
+                    name
+                );
+            } else {
+                eprintln!("\n{} failed, rolling back the installation\n", name);
+                state.rollback(ex)?;
+            }
+            return Err(err);
+        }
+
+        state.mark_done(name);
+        if !dry_run {
+            state.save()?;
+        }
+    }
+
+    if !dry_run {
+        state.remove()?;
+    }
 
     Ok(())
 }
 
 fn main() -> Result<()> {
     match parse_args::parse_args()? {
-        SailState::Start => {
-            start(parse_conf::parse_conf()?)?;
+        SailState::Start {
+            dry_run,
+            resume,
+            keep_on_failure,
+            keep_installed,
+            configs,
+            sets,
+            output,
+        } => {
+            let resumed = if resume {
+                Some(InstallState::load()?)
+            } else {
+                None
+            };
+            let layout = resumed.as_ref().map(|state| state.get_layout().clone());
+            let sail = parse_conf::parse_conf(&configs, &sets, layout)?;
+            start(
+                sail,
+                resumed,
+                dry_run,
+                keep_on_failure,
+                keep_installed,
+                output,
+            )?;
+        }
+        SailState::Exec { script, vars } => {
+            post_scripts::exec(&script, &vars)?;
+        }
+        SailState::List => {
+            post_scripts::list();
+        }
+        SailState::Init { interactive, force } => {
+            init::init(interactive, force)?;
+        }
+        SailState::ConfigCheck { paths } => {
+            parse_conf::check_conf(&paths)?;
+        }
+        SailState::CacheBuild {
+            dir,
+            dry_run,
+            configs,
+            sets,
+        } => {
+            let conf = parse_conf::read_conf(&configs, &sets)?;
+            if dry_run {
+                cache::build(&conf, &dir, &mut DryRun)?;
+            } else {
+                setup::check_as_root()?;
+                cache::build(&conf, &dir, &mut Real::default())?;
+            }
         }
-        SailState::Exec => {}
-        SailState::List => {}
     }
 
     Ok(())
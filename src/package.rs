@@ -0,0 +1,560 @@
+use crate::runner::{cmd, Executor};
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// Comparison of a versioned dependency
+#[derive(Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Ge,
+    Gt,
+    Le,
+    Lt,
+}
+
+/// Entry of "Depends On" or "Provides", e.g. "linux=6.6.1.arch1-1", "sh>=5"
+/// or "kmod"
+#[derive(Debug, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub version: Option<(Op, String)>,
+}
+
+impl Dependency {
+    fn parse(dependency: &str) -> Self {
+        let Some(at) = dependency.find(['<', '>', '=']) else {
+            return Self {
+                name: dependency.to_owned(),
+                version: None,
+            };
+        };
+        let (name, constraint) = dependency.split_at(at);
+        let (op, version) = [
+            (">=", Op::Ge),
+            ("<=", Op::Le),
+            ("=", Op::Eq),
+            (">", Op::Gt),
+            ("<", Op::Lt),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| Some((op, constraint.strip_prefix(prefix)?)))
+        .unwrap_or((Op::Eq, constraint));
+
+        Self {
+            name: name.to_owned(),
+            version: Some((op, version.to_owned())),
+        }
+    }
+}
+
+/// Package of the sync databases, parsed from `pacman -Si`
+#[derive(Debug, PartialEq)]
+pub struct Package {
+    pub repository: String,
+    pub name: String,
+    pub version: String,
+    pub depends: Vec<Dependency>,
+    pub provides: Vec<Dependency>,
+}
+
+impl Package {
+    /// Dependency of the package on `name`
+    pub fn get_depend(&self, name: &str) -> Option<&Dependency> {
+        self.depends.iter().find(|depend| depend.name == name)
+    }
+}
+
+/// Packages of `pacman -Si` output, one per repository listing it
+pub fn parse_packages(out: &str) -> Result<Vec<Package>> {
+    let mut packages = Vec::new();
+
+    for record in out.split("\n\n") {
+        // Values too long for the terminal continue on indented lines
+        let mut fields: Vec<(&str, String)> = Vec::new();
+        for line in record.lines() {
+            match (line.starts_with(char::is_whitespace), fields.last_mut()) {
+                (true, Some((_, value))) => {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                _ => {
+                    if let Some((key, value)) = line.split_once(':') {
+                        fields.push((key.trim(), value.trim().to_owned()));
+                    }
+                }
+            }
+        }
+        if fields.is_empty() {
+            continue;
+        }
+
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let list = |key: &str| match field(key) {
+            None | Some("None") => Vec::new(),
+            Some(value) => value.split_whitespace().map(Dependency::parse).collect(),
+        };
+
+        let name = field("Name").context("pacman -Si shows a package without a name")?;
+        let version =
+            field("Version").with_context(|| format!("pacman -Si shows no version of {}", name))?;
+        packages.push(Package {
+            repository: field("Repository").unwrap_or_default().to_owned(),
+            name: name.to_owned(),
+            version: version.to_owned(),
+            depends: list("Depends On"),
+            provides: list("Provides"),
+        });
+    }
+
+    Ok(packages)
+}
+
+/// Packages named `name` in the sync databases, `options` are passed to
+/// pacman, e.g. --config of another pacman.conf
+pub fn find(ex: &mut dyn Executor, name: &str, options: &[String]) -> Result<Vec<Package>> {
+    let out = ex.query(&cmd!(%"env LC_ALL=C pacman -Si", options, name))?;
+    let packages = parse_packages(&out)?;
+    if !packages.iter().any(|package| package.name == name) {
+        bail!("{} isn't in the sync databases", name);
+    }
+
+    Ok(packages)
+}
+
+/// Packages of the `desc` entries of a repository database, e.g. from
+/// `bsdtar -xOf sail.db '*/desc'`, where a "%NAME%" line is followed by
+/// the values of the field, one per line
+pub fn parse_repo_db(out: &str, repository: &str) -> Result<Vec<Package>> {
+    let mut packages = Vec::new();
+
+    // Every entry starts with the file name of the package
+    for entry in out.split("%FILENAME%") {
+        let mut fields: Vec<(&str, Vec<&str>)> = Vec::new();
+        for line in entry.lines() {
+            match line
+                .strip_prefix('%')
+                .and_then(|line| line.strip_suffix('%'))
+            {
+                Some(key) => fields.push((key, Vec::new())),
+                None if line.is_empty() => {}
+                None => {
+                    if let Some((_, values)) = fields.last_mut() {
+                        values.push(line);
+                    }
+                }
+            }
+        }
+        if fields.is_empty() {
+            continue;
+        }
+
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(name, _)| *name == key)
+                .and_then(|(_, values)| values.first().copied())
+        };
+        let list = |key: &str| match fields.iter().find(|(name, _)| *name == key) {
+            Some((_, values)) => values
+                .iter()
+                .map(|value| Dependency::parse(value))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let name = field("NAME").context("Repository database has a package without a name")?;
+        let version = field("VERSION")
+            .with_context(|| format!("Repository database has no version of {}", name))?;
+        packages.push(Package {
+            repository: repository.to_owned(),
+            name: name.to_owned(),
+            version: version.to_owned(),
+            depends: list("DEPENDS"),
+            provides: list("PROVIDES"),
+        });
+    }
+
+    Ok(packages)
+}
+
+/// Packages named `name` in the repository database `db`, read without
+/// pacman so it takes no pacman.conf nor `pacman -Sy`
+pub fn find_in_db(ex: &mut dyn Executor, db: &str, name: &str) -> Result<Vec<Package>> {
+    // pacman.conf names the repository after its database, sail.db is [sail]
+    let repository = Path::new(db)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let out = ex.query(&cmd!(%"bsdtar -xOf", db, "*/desc"))?;
+    let packages: Vec<_> = parse_repo_db(&out, &repository)?
+        .into_iter()
+        .filter(|package| package.name == name)
+        .collect();
+    if packages.is_empty() {
+        bail!("{} isn't in {}", name, db);
+    }
+
+    Ok(packages)
+}
+
+/// File name of package `name` at `version` in the Arch Linux Archive
+pub fn archive_file(name: &str, version: &str) -> String {
+    format!("{}-{}-x86_64.pkg.tar.zst", name, version)
+}
+
+/// Arch Linux Archive URL of package `name` at `version`, its signature is
+/// at the same URL plus ".sig"
+pub fn archive_url(name: &str, version: &str) -> String {
+    let initial = name.chars().next().unwrap_or('_');
+
+    format!(
+        "https://archive.archlinux.org/packages/{}/{}/{}",
+        initial,
+        name,
+        archive_file(name, version)
+    )
+}
+
+/// Download package `name` at `version` from the Arch Linux Archive to
+/// `dir` and verify its signature, the path of the package is returned
+pub fn fetch_archived(
+    ex: &mut dyn Executor,
+    name: &str,
+    version: &str,
+    dir: &str,
+) -> Result<String> {
+    ex.log(&format!("Download {} {} from archive", name, version));
+    let url = archive_url(name, version);
+    let file = format!("{}/{}", dir, archive_file(name, version));
+    let sig = format!("{}.sig", file);
+    ex.run(&cmd!(%"curl -fL -o", &file, &url))?;
+    ex.run(&cmd!(%"curl -fL -o", &sig, format!("{}.sig", url)))?;
+
+    ex.log(&format!("Verify signature of {} {}", name, version));
+    ex.run(&cmd!(%"pacman-key --verify", sig, &file))?;
+
+    Ok(file)
+}
+
+/// Where the kernel matching the ZFS module comes from. pacman takes a bare
+/// name from the first repository having it, so both are installed from the
+/// repository they were matched in.
+#[derive(Debug, PartialEq)]
+pub enum KernelSource {
+    /// `version` in `repository` of the sync databases
+    Repo {
+        repository: String,
+        version: String,
+        zfs_repository: String,
+    },
+    /// An older or newer version, from the Arch Linux Archive
+    Archive {
+        version: String,
+        zfs_repository: String,
+    },
+}
+
+impl KernelSource {
+    /// Repository of the zfs package built against the kernel
+    pub fn get_zfs_repository(&self) -> &str {
+        match self {
+            KernelSource::Repo { zfs_repository, .. }
+            | KernelSource::Archive { zfs_repository, .. } => zfs_repository,
+        }
+    }
+}
+
+/// Kernel for the ZFS module, the `zfs` packages are built against an
+/// exact version of the `kernel` packages, except the DKMS one
+pub fn pick_kernel(kernels: &[Package], zfs: &[Package]) -> Result<KernelSource> {
+    let kernel = kernels.first().context("No kernel package")?;
+    let first_zfs = zfs.first().context("No zfs package")?;
+    if first_zfs.name.ends_with("-dkms") {
+        return Ok(KernelSource::Repo {
+            repository: kernel.repository.clone(),
+            version: kernel.version.clone(),
+            zfs_repository: first_zfs.repository.clone(),
+        });
+    }
+
+    let mut required = Vec::new();
+    for zfs in zfs {
+        match zfs
+            .get_depend(&kernel.name)
+            .and_then(|depend| depend.version.as_ref())
+        {
+            Some((Op::Eq, version)) => required.push((zfs, version)),
+            Some((op, version)) => bail!(
+                "{} of {} depends on {} {:?} {} instead of an exact version",
+                zfs.name,
+                zfs.repository,
+                kernel.name,
+                op,
+                version
+            ),
+            None => {}
+        }
+    }
+
+    for (zfs, version) in &required {
+        if let Some(kernel) = kernels.iter().find(|kernel| kernel.version == **version) {
+            return Ok(KernelSource::Repo {
+                repository: kernel.repository.clone(),
+                version: version.to_string(),
+                zfs_repository: zfs.repository.clone(),
+            });
+        }
+    }
+    match required.first() {
+        Some((zfs, version)) => Ok(KernelSource::Archive {
+            version: version.to_string(),
+            zfs_repository: zfs.repository.clone(),
+        }),
+        None => bail!(
+            "No {} package depends on an exact {} version, {} {} can't be paired with it",
+            first_zfs.name,
+            kernel.name,
+            kernel.name,
+            kernel.version
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINUX: &str = "\
+Repository      : core
+Name            : linux
+Version         : 6.6.1.arch1-1
+Description     : The Linux kernel and modules
+Architecture    : x86_64
+URL             : https://github.com/archlinux/linux/commits/v6.6.1-arch1
+Licenses        : GPL2
+Groups          : None
+Provides        : VIRTUALBOX-GUEST-MODULES  WIREGUARD-MODULE  KSMBD-MODULE
+Depends On      : coreutils  kmod  initramfs
+Optional Deps   : wireless-regdb: to set the correct wireless channels of your country
+                  linux-firmware: firmware images needed for some devices
+Conflicts With  : None
+Replaces        : virtualbox-guest-modules-arch  wireguard-arch
+Download Size   : 134.92 MiB
+Installed Size  : 132.03 MiB
+Packager        : Jan Alexander Steffens (heftig) <heftig@archlinux.org>
+Build Date      : Wed 08 Nov 2023 05:22:04 PM UTC
+Validated By    : MD5 Sum  SHA-256 Sum  Signature
+
+";
+
+    const ZFS_LINUX: &str = "\
+Repository      : archzfs
+Name            : zfs-linux
+Version         : 2.2.0_6.5.9.arch2.1-1
+Description     : Kernel modules for the Zettabyte File System.
+Architecture    : x86_64
+URL             : https://openzfs.org/
+Licenses        : CDDL
+Groups          : archzfs-linux
+Provides        : zfs  ZFS-MODULE=2.2.0  SPL-MODULE=2.2.0
+Depends On      : kmod  zfs-utils=2.2.0
+                  linux=6.5.9.arch2-1
+Optional Deps   : None
+Conflicts With  : zfs-dkms  spl-linux
+Replaces        : spl-linux
+Validated By    : SHA-256 Sum  Signature
+
+";
+
+    const ZFS_DKMS: &str = "\
+Repository      : archzfs
+Name            : zfs-dkms
+Version         : 2.2.0-1
+Provides        : zfs  ZFS-MODULE=2.2.0  SPL-MODULE=2.2.0  spl-dkms
+Depends On      : zfs-utils=2.2.0  lsb-release  dkms
+";
+
+    fn depend(name: &str, version: Option<(Op, &str)>) -> Dependency {
+        Dependency {
+            name: name.to_owned(),
+            version: version.map(|(op, version)| (op, version.to_owned())),
+        }
+    }
+
+    #[test]
+    fn parse_records() {
+        let packages = parse_packages(&format!("{}{}", LINUX, ZFS_LINUX)).unwrap();
+        assert_eq!(packages.len(), 2);
+
+        let linux = &packages[0];
+        assert_eq!(linux.repository, "core");
+        assert_eq!(linux.name, "linux");
+        assert_eq!(linux.version, "6.6.1.arch1-1");
+        assert_eq!(linux.depends[1], depend("kmod", None));
+        assert_eq!(linux.provides.len(), 3);
+
+        let zfs = &packages[1];
+        assert_eq!(zfs.name, "zfs-linux");
+        assert_eq!(
+            zfs.get_depend("linux"),
+            Some(&depend("linux", Some((Op::Eq, "6.5.9.arch2-1"))))
+        );
+        assert_eq!(
+            zfs.provides[1],
+            depend("ZFS-MODULE", Some((Op::Eq, "2.2.0")))
+        );
+    }
+
+    #[test]
+    fn parse_repo_db_entries() {
+        let out = "%FILENAME%\nlinux-6.6.1.arch1-1-x86_64.pkg.tar.zst\n\n\
+                   %NAME%\nlinux\n\n%VERSION%\n6.6.1.arch1-1\n\n\
+                   %DEPENDS%\ncoreutils\nkmod\ninitramfs\n\n\
+                   %FILENAME%\nzfs-linux-2.2.0_6.5.9.arch2.1-1-x86_64.pkg.tar.zst\n\n\
+                   %NAME%\nzfs-linux\n\n%VERSION%\n2.2.0_6.5.9.arch2.1-1\n\n\
+                   %DEPENDS%\nkmod\nzfs-utils=2.2.0\nlinux=6.5.9.arch2-1\n\n\
+                   %PROVIDES%\nzfs\nZFS-MODULE=2.2.0\n\n";
+        let packages = parse_repo_db(out, "sail").unwrap();
+        assert_eq!(packages.len(), 2);
+
+        let linux = &packages[0];
+        assert_eq!(linux.repository, "sail");
+        assert_eq!(linux.name, "linux");
+        assert_eq!(linux.version, "6.6.1.arch1-1");
+        assert_eq!(linux.depends.len(), 3);
+        assert!(linux.provides.is_empty());
+
+        let zfs = &packages[1];
+        assert_eq!(
+            zfs.get_depend("linux"),
+            Some(&depend("linux", Some((Op::Eq, "6.5.9.arch2-1"))))
+        );
+        assert_eq!(zfs.provides[0], depend("zfs", None));
+        assert!(parse_repo_db("%FILENAME%\nx.pkg.tar.zst\n\n%VERSION%\n1-1\n", "sail").is_err());
+    }
+
+    #[test]
+    fn parse_constraints() {
+        assert_eq!(
+            Dependency::parse("sh>=5"),
+            depend("sh", Some((Op::Ge, "5")))
+        );
+        assert_eq!(
+            Dependency::parse("glibc<3"),
+            depend("glibc", Some((Op::Lt, "3")))
+        );
+        assert_eq!(Dependency::parse("kmod"), depend("kmod", None));
+        assert!(parse_packages("Name : linux\n").is_err());
+        assert!(parse_packages("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn kernel_from_repo_or_archive() {
+        let zfs = parse_packages(ZFS_LINUX).unwrap();
+
+        let matching = LINUX.replace("6.6.1.arch1-1", "6.5.9.arch2-1");
+        let kernels = parse_packages(&matching).unwrap();
+        assert_eq!(
+            pick_kernel(&kernels, &zfs).unwrap(),
+            KernelSource::Repo {
+                repository: "core".to_owned(),
+                version: "6.5.9.arch2-1".to_owned(),
+                zfs_repository: "archzfs".to_owned(),
+            }
+        );
+
+        let kernels = parse_packages(LINUX).unwrap();
+        assert_eq!(
+            pick_kernel(&kernels, &zfs).unwrap(),
+            KernelSource::Archive {
+                version: "6.5.9.arch2-1".to_owned(),
+                zfs_repository: "archzfs".to_owned(),
+            }
+        );
+
+        // The testing repository has the matching pair
+        let testing = ZFS_LINUX
+            .replace("archzfs", "archzfs-testing")
+            .replace("6.5.9.arch2-1", "6.6.1.arch1-1");
+        let zfs = parse_packages(&format!("{}{}", ZFS_LINUX, testing)).unwrap();
+        assert_eq!(
+            pick_kernel(&kernels, &zfs).unwrap(),
+            KernelSource::Repo {
+                repository: "core".to_owned(),
+                version: "6.6.1.arch1-1".to_owned(),
+                zfs_repository: "archzfs-testing".to_owned(),
+            }
+        );
+
+        // and so has the testing kernel repository, after core
+        let core_testing = LINUX
+            .replace("core", "core-testing")
+            .replace("6.6.1.arch1-1", "6.5.9.arch2-1");
+        let kernels = parse_packages(&format!("{}{}", LINUX, core_testing)).unwrap();
+        let zfs = parse_packages(ZFS_LINUX).unwrap();
+        assert_eq!(
+            pick_kernel(&kernels, &zfs).unwrap(),
+            KernelSource::Repo {
+                repository: "core-testing".to_owned(),
+                version: "6.5.9.arch2-1".to_owned(),
+                zfs_repository: "archzfs".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn dkms_takes_repo_kernel() {
+        let kernels = parse_packages(LINUX).unwrap();
+        let zfs = parse_packages(ZFS_DKMS).unwrap();
+        assert_eq!(
+            pick_kernel(&kernels, &zfs).unwrap(),
+            KernelSource::Repo {
+                repository: "core".to_owned(),
+                version: "6.6.1.arch1-1".to_owned(),
+                zfs_repository: "archzfs".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn no_kernel_dependency() {
+        let kernels = parse_packages(LINUX).unwrap();
+        let zfs = parse_packages(&ZFS_LINUX.replace("linux=6.5.9.arch2-1", "")).unwrap();
+        let err = pick_kernel(&kernels, &zfs).unwrap_err();
+        assert!(
+            err.to_string().starts_with("No zfs-linux package"),
+            "{}",
+            err
+        );
+
+        let zfs = parse_packages(&ZFS_LINUX.replace("linux=", "linux>=")).unwrap();
+        assert!(pick_kernel(&kernels, &zfs).is_err());
+    }
+
+    #[test]
+    fn archive_urls() {
+        assert_eq!(
+            archive_url("linux", "6.5.9.arch2-1"),
+            "https://archive.archlinux.org/packages/l/linux/linux-6.5.9.arch2-1-x86_64.pkg.tar.zst"
+        );
+        assert_eq!(
+            archive_url("linux-lts-headers", "6.1.60-1"),
+            "https://archive.archlinux.org/packages/l/linux-lts-headers/\
+             linux-lts-headers-6.1.60-1-x86_64.pkg.tar.zst"
+        );
+        assert_eq!(
+            archive_url("linux-zen", "6.5.9.zen2-1"),
+            "https://archive.archlinux.org/packages/l/linux-zen/linux-zen-6.5.9.zen2-1-x86_64.pkg.tar.zst"
+        );
+        assert_eq!(
+            archive_url("linux-hardened", "6.5.9.hardened1-1"),
+            "https://archive.archlinux.org/packages/l/linux-hardened/\
+             linux-hardened-6.5.9.hardened1-1-x86_64.pkg.tar.zst"
+        );
+    }
+}
@@ -1,12 +1,56 @@
 use crate::parse_conf;
-use anyhow::Result;
+use anyhow::{bail, Error, Result};
 use argh::FromArgs;
-use std::path::Path;
+use std::{path::Path, str::FromStr};
+
+/// How `start` reports its progress
+pub enum Output {
+    /// Log messages and the output of the commands, for a terminal
+    Text,
+    /// A JSON event per line for every step and command, for tools
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = Error;
+
+    fn from_str(output: &str) -> Result<Self> {
+        match output {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => bail!(r#""{}" isn't an output format, use text or json"#, output),
+        }
+    }
+}
 
 pub enum SailState {
-    Start,
-    Exec,
+    Start {
+        dry_run: bool,
+        resume: bool,
+        keep_on_failure: bool,
+        keep_installed: bool,
+        configs: Vec<String>,
+        sets: Vec<String>,
+        output: Output,
+    },
+    Exec {
+        script: String,
+        vars: Vec<String>,
+    },
     List,
+    Init {
+        interactive: bool,
+        force: bool,
+    },
+    ConfigCheck {
+        paths: Vec<String>,
+    },
+    CacheBuild {
+        dir: String,
+        dry_run: bool,
+        configs: Vec<String>,
+        sets: Vec<String>,
+    },
 }
 
 #[derive(FromArgs)]
@@ -22,12 +66,46 @@ enum SailSubCommand {
     Start(StartCmd),
     Exec(ExecCmd),
     List(ListCmd),
+    Init(InitCmd),
+    Config(ConfigCmd),
+    Cache(CacheCmd),
 }
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "start")]
 /// start Arch linux installation
-struct StartCmd {}
+struct StartCmd {
+    #[argh(switch)]
+    /// print the commands and file writes without executing them
+    dry_run: bool,
+
+    #[argh(switch)]
+    /// continue an interrupted installation from the first unfinished step
+    resume: bool,
+
+    #[argh(switch)]
+    /// don't roll back the changes when a step fails, for debugging
+    keep_on_failure: bool,
+
+    #[argh(switch)]
+    /// don't roll back once pacstrap is done: a later failure keeps the
+    /// installed system and the install state, to fix and --resume
+    keep_installed: bool,
+
+    #[argh(option, short = 'c')]
+    /// config file, ./sail.toml by default, later ones override earlier
+    /// ones (repeatable)
+    config: Vec<String>,
+
+    #[argh(option)]
+    /// override a config key, e.g. --set hostname=nas (repeatable)
+    set: Vec<String>,
+
+    #[argh(option, default = "Output::Text")]
+    /// progress as text, or json for an event per line on stdout, with
+    /// --dry-run the commands have no exit status nor output
+    output: Output,
+}
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "exec")]
@@ -36,6 +114,10 @@ struct ExecCmd {
     #[argh(option, short = 's')]
     /// specify script name to execute
     script: String,
+
+    #[argh(option, short = 'v')]
+    /// override a script variable, e.g. -v my_user=alice (repeatable)
+    var: Vec<String>,
 }
 
 #[derive(FromArgs)]
@@ -43,19 +125,132 @@ struct ExecCmd {
 /// list all available script
 struct ListCmd {}
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "init")]
+/// write a commented sail.toml
+struct InitCmd {
+    #[argh(switch)]
+    /// ask for the disks, kernel, hostname, timezone and users
+    interactive: bool,
+
+    #[argh(switch)]
+    /// overwrite an existing sail.toml
+    force: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "config")]
+/// work with sail.toml
+struct ConfigCmd {
+    #[argh(subcommand)]
+    configsubs: ConfigSubCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum ConfigSubCommand {
+    Check(ConfigCheckCmd),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "check")]
+/// check a config without changing anything, fails on any problem
+struct ConfigCheckCmd {
+    #[argh(positional)]
+    /// config files to check, merged like with `start --config`,
+    /// ./sail.toml by default
+    paths: Vec<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "cache")]
+/// work with a local repository for offline installs
+struct CacheCmd {
+    #[argh(subcommand)]
+    cachesubs: CacheSubCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum CacheSubCommand {
+    Build(CacheBuildCmd),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "build")]
+/// download and build every package of an install into a directory, set
+/// it as path of [repo] to install without internet access
+struct CacheBuildCmd {
+    #[argh(positional)]
+    /// directory of the repository, packages already in it are kept
+    dir: String,
+
+    #[argh(switch)]
+    /// print the commands without executing them
+    dry_run: bool,
+
+    #[argh(option, short = 'c')]
+    /// config file the packages are picked from, merged like with
+    /// `start --config`, the defaults when missing (repeatable)
+    config: Vec<String>,
+
+    #[argh(option)]
+    /// override a config key, e.g. --set linvar=LinuxLts (repeatable)
+    set: Vec<String>,
+}
+
 pub fn parse_args() -> Result<SailState> {
     let sail_args: SailArgs = argh::from_env();
 
     let sailsubs = sail_args.sailsubs;
     match sailsubs {
-        SailSubCommand::Start(_) => {
-            let conf_path = Path::new("sail.toml");
-            if !conf_path.is_file() {
-                parse_conf::generate_conf()?;
+        SailSubCommand::Start(startopt) => {
+            let mut configs = startopt.config;
+            if configs.is_empty() {
+                let conf_path = Path::new("sail.toml");
+                if !conf_path.is_file() {
+                    parse_conf::generate_conf()?;
+                }
+                configs.push("sail.toml".to_owned());
             }
-            Ok(SailState::Start)
+            Ok(SailState::Start {
+                dry_run: startopt.dry_run,
+                resume: startopt.resume,
+                keep_on_failure: startopt.keep_on_failure,
+                keep_installed: startopt.keep_installed,
+                configs,
+                sets: startopt.set,
+                output: startopt.output,
+            })
         }
-        SailSubCommand::Exec(execopt) => Ok(SailState::Exec),
+        SailSubCommand::Exec(execopt) => Ok(SailState::Exec {
+            script: execopt.script,
+            vars: execopt.var,
+        }),
         SailSubCommand::List(_) => Ok(SailState::List),
+        SailSubCommand::Init(initopt) => Ok(SailState::Init {
+            interactive: initopt.interactive,
+            force: initopt.force,
+        }),
+        SailSubCommand::Config(configopt) => match configopt.configsubs {
+            ConfigSubCommand::Check(checkopt) => {
+                let mut paths = checkopt.paths;
+                if paths.is_empty() {
+                    paths.push("sail.toml".to_owned());
+                }
+                Ok(SailState::ConfigCheck { paths })
+            }
+        },
+        SailSubCommand::Cache(cacheopt) => match cacheopt.cachesubs {
+            CacheSubCommand::Build(buildopt) => {
+                let dir = std::path::absolute(&buildopt.dir)?;
+                Ok(SailState::CacheBuild {
+                    dir: dir.to_string_lossy().into_owned(),
+                    dry_run: buildopt.dry_run,
+                    configs: buildopt.config,
+                    sets: buildopt.set,
+                })
+            }
+        },
     }
 }
@@ -1,42 +1,816 @@
 use crate::{
-    sail::{LinuxVariant, Sail},
+    package,
+    runner::Real,
+    sail::{
+        Dataset, Encryption, Layout, LinuxVariant, Packages, Repo, RootPassword, Sail, Topology,
+        User,
+    },
+    size::Size,
     StorageType, ZfsType,
 };
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde_derive::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, ops::Range};
+use toml_edit::{ImDocument, Item, TableLike, Value};
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    linvar: LinuxVariant,
-    zfs_type: ZfsType,
-    storage_type: StorageType,
-    disk: String,
-    partsize_esp: String,
-    partsize_bpool: String,
+    pub linvar: LinuxVariant,
+    pub zfs_type: ZfsType,
+    pub storage_type: StorageType,
+    /// Single disk to install to, or use `disks` and `topology`
+    pub disk: String,
+    /// Disks to install to, each one is partitioned identically
+    pub disks: Vec<String>,
+    pub topology: Topology,
+    /// Erase everything on the disks instead of adding partitions after
+    /// the existing ones
+    pub wipe: bool,
+    pub partsize_esp: Size,
+    pub partsize_bpool: Size,
+    pub bpool_name: String,
+    pub rpool_name: String,
+    pub hostname: String,
+    pub timezone: String,
+    /// The first one is the system locale
+    pub locales: Vec<String>,
+    pub keymap: String,
+    pub root_shell: String,
+    pub root_password: RootPassword,
+    /// Used with `root_password = "Hashed"`, e.g. from `openssl passwd -6`
+    pub root_password_hash: String,
+    pub users: Vec<User>,
+    /// Pool properties (-o) of zpool create, merged over the defaults,
+    /// e.g. `ashift = "13"`
+    pub bpool_properties: BTreeMap<String, String>,
+    /// Filesystem properties (-O) of zpool create, merged over the
+    /// defaults, e.g. `compression = "zstd"`
+    pub bpool_fs_properties: BTreeMap<String, String>,
+    pub rpool_properties: BTreeMap<String, String>,
+    pub rpool_fs_properties: BTreeMap<String, String>,
+    pub encryption: Encryption,
+    /// Replaces the whole default layout when set
+    pub datasets: Vec<Dataset>,
+    pub packages: Packages,
+    pub repo: Repo,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            linvar: LinuxVariant::default(),
+            zfs_type: ZfsType::default(),
+            storage_type: StorageType::default(),
+            disk: String::new(),
+            disks: Vec::new(),
+            topology: Topology::default(),
+            wipe: false,
+            partsize_esp: Size::Bytes(512 << 20),
+            partsize_bpool: Size::Bytes(4 << 30),
+            bpool_name: "bpool".to_owned(),
+            rpool_name: "rpool".to_owned(),
+            hostname: "lbox".to_owned(),
+            timezone: "Asia/Jakarta".to_owned(),
+            locales: vec!["en_US.UTF-8".to_owned()],
+            keymap: "us".to_owned(),
+            root_shell: "/bin/zsh".to_owned(),
+            root_password: RootPassword::default(),
+            root_password_hash: String::new(),
+            users: Vec::new(),
+            bpool_properties: BTreeMap::new(),
+            bpool_fs_properties: BTreeMap::new(),
+            rpool_properties: BTreeMap::new(),
+            rpool_fs_properties: BTreeMap::new(),
+            encryption: Encryption::default(),
+            datasets: Dataset::default_layout(),
+            packages: Packages::default(),
+            repo: Repo::default(),
+        }
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+impl Config {
+    /// sail.toml of the config, with the encryption passphrase and the
+    /// password hashes redacted
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        fn redact(table: &mut toml::Table, key: &str) {
+            if let Some(toml::Value::String(secret)) = table.get_mut(key) {
+                if !secret.is_empty() {
+                    *secret = REDACTED.to_owned();
+                }
+            }
+        }
+
+        let mut conf = toml::Table::try_from(self)?;
+        redact(&mut conf, "root_password_hash");
+        if let Some(toml::Value::Table(encryption)) = conf.get_mut("encryption") {
+            redact(encryption, "passphrase");
+        }
+        if let Some(toml::Value::Array(users)) = conf.get_mut("users") {
+            for user in users {
+                if let toml::Value::Table(user) = user {
+                    redact(user, "password_hash");
+                }
+            }
+        }
+
+        Ok(toml::to_string(&conf)?)
+    }
+}
+
+/// TOML basic string of `value`
+fn quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str(r#"\""#),
+            '\\' => quoted.push_str(r"\\"),
+            '\n' => quoted.push_str(r"\n"),
+            '\t' => quoted.push_str(r"\t"),
+            c if c.is_control() => quoted.push_str(&format!(r"\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+fn quote_list(values: &[String]) -> String {
+    let values: Vec<String> = values.iter().map(|value| quote(value)).collect();
+
+    format!("[{}]", values.join(", "))
+}
+
+/// sail.toml with `conf` and a comment on every option, the pool
+/// properties, encryption and datasets are left to their defaults with
+/// commented examples
+pub fn render(conf: &Config) -> String {
+    let mut out = String::new();
+    let mut line = |line: &str| {
+        out.push_str(line);
+        out.push('\n');
+    };
+
+    line("# Configuration of `sail start`, commented lines are examples");
+    line("");
+    line("# Linux, LinuxLts, LinuxZen or LinuxHardened");
+    line(&format!(
+        "linvar = {}",
+        quote(&format!("{:?}", conf.linvar))
+    ));
+    line("# Normal for the zfs-linux* package of the kernel, or Dkms");
+    line(&format!(
+        "zfs_type = {}",
+        quote(&format!("{:?}", conf.zfs_type))
+    ));
+    line("# Ssd or Hdd, Ssd discards the disks and enables periodic trim");
+    line(&format!(
+        "storage_type = {}",
+        quote(&format!("{:?}", conf.storage_type))
+    ));
+    line("");
+
+    line("# Disk to install to, preferably a /dev/disk/by-id path");
+    let disks = if conf.disks.is_empty() {
+        vec![
+            "/dev/disk/by-id/...".to_owned(),
+            "/dev/disk/by-id/...".to_owned(),
+        ]
+    } else {
+        conf.disks.clone()
+    };
+    let (disk, multi) = if conf.disks.is_empty() {
+        ("", "# ")
+    } else {
+        ("# ", "")
+    };
+    line(&format!("{}disk = {}", disk, quote(&conf.disk)));
+    line("# Or several disks, partitioned identically, and the vdev layout of");
+    line("# the pools over them: Single, Mirror, Raidz1, Raidz2 or Raidz3");
+    line(&format!("{}disks = {}", multi, quote_list(&disks)));
+    let topology = match conf.topology {
+        Topology::Single => "Mirror".to_owned(),
+        ref topology => format!("{:?}", topology),
+    };
+    line(&format!("{}topology = {}", multi, quote(&topology)));
+    line("# Erase everything on the disks instead of adding partitions after the");
+    line("# existing ones");
+    line(&format!("wipe = {}", conf.wipe));
+    line("# e.g. 512M, 1.5G, 2GiB or 25% of the free space, rpool takes the rest.");
+    line("# The free space is the largest free block of the smallest disk, or the");
+    line("# whole disk with wipe");
+    line(&format!(
+        "partsize_esp = {}",
+        quote(&conf.partsize_esp.to_string())
+    ));
+    line(&format!(
+        "partsize_bpool = {}",
+        quote(&conf.partsize_bpool.to_string())
+    ));
+    line("");
+
+    line(&format!("bpool_name = {}", quote(&conf.bpool_name)));
+    line(&format!("rpool_name = {}", quote(&conf.rpool_name)));
+    line("# Pool (-o) and filesystem (-O) properties of zpool create, merged over");
+    line("# the defaults");
+    line("# [rpool_properties]");
+    line("# ashift = \"13\"");
+    line("# [rpool_fs_properties]");
+    line("# compression = \"zstd\"");
+    line("");
+
+    line(&format!("hostname = {}", quote(&conf.hostname)));
+    line("# Path below /usr/share/zoneinfo");
+    line(&format!("timezone = {}", quote(&conf.timezone)));
+    line("# The first one is the system locale");
+    line(&format!("locales = {}", quote_list(&conf.locales)));
+    line(&format!("keymap = {}", quote(&conf.keymap)));
+    line(&format!("root_shell = {}", quote(&conf.root_shell)));
+    line("# Prompt asks for it before installing, Hashed takes root_password_hash");
+    line("# (e.g. from `openssl passwd -6`), Locked disables root logins");
+    line(&format!(
+        "root_password = {}",
+        quote(&format!("{:?}", conf.root_password))
+    ));
+    line(&format!(
+        "root_password_hash = {}",
+        quote(&conf.root_password_hash)
+    ));
+    line("");
+
+    line("# Packages besides the kernel and zfs: the Minimal, Desktop or Server");
+    line("# profile, extra ones, and ones left out of the profile or the firmware.");
+    line("# Only the microcode of the CPU is installed.");
+    line("[packages]");
+    line(&format!(
+        "profile = {}",
+        quote(&format!("{:?}", conf.packages.profile))
+    ));
+    if conf.packages.extra.is_empty() {
+        line("# extra = [\"htop\", \"openssh\"]");
+    } else {
+        line(&format!("extra = {}", quote_list(&conf.packages.extra)));
+    }
+    if conf.packages.exclude.is_empty() {
+        line("# exclude = [\"os-prober\"]");
+    } else {
+        line(&format!("exclude = {}", quote_list(&conf.packages.exclude)));
+    }
+    line("");
+
+    line("# Encryption of rpool, key is None, Passphrase (prompted when empty) or");
+    line("# Keyfile (32 random bytes), which needs embed_keyfile = true as the key is");
+    line("# copied into the initramfs on the unencrypted bpool");
+    line("# [encryption]");
+    line("# key = \"Passphrase\"");
+    line("# cipher = \"Aes256Gcm\"");
+    line("");
+
+    line("# Install without internet access from a local repository made by");
+    line("# `sail cache build`");
+    if conf.repo.path.is_empty() {
+        line("# [repo]");
+        line("# path = \"/run/media/sail-repo\"");
+    } else {
+        line("[repo]");
+        line(&format!("path = {}", quote(&conf.repo.path)));
+    }
+    line("");
+
+    line("# Datasets below rpool/arch/DATA/default, replacing the whole default");
+    line("# layout when set");
+    line("# [[datasets]]");
+    line("# name = \"var/lib/docker\"");
+    line("# canmount = \"On\"");
+    line("");
+
+    line("# Users, the account is locked without password_hash, sudo adds wheel");
+    if conf.users.is_empty() {
+        line("# [[users]]");
+        line("# name = \"alice\"");
+        line("# groups = [\"audio\", \"video\"]");
+        line("# sudo = true");
+    }
+    for user in &conf.users {
+        line("[[users]]");
+        line(&format!("name = {}", quote(&user.name)));
+        line(&format!("groups = {}", quote_list(&user.groups)));
+        line(&format!("shell = {}", quote(&user.shell)));
+        if let Some(hash) = &user.password_hash {
+            line(&format!("password_hash = {}", quote(hash)));
+        }
+        line(&format!(
+            "ssh_authorized_keys = {}",
+            quote_list(&user.ssh_authorized_keys)
+        ));
+        line(&format!("sudo = {}", user.sudo));
+    }
+
+    out
 }
 
 pub fn generate_conf() -> Result<()> {
-    let _: Config = confy::load_path("sail.toml")?;
+    fs::write("sail.toml", render(&Config::default()))?;
 
     bail!(
         "./sail.toml not found, \
           generating a new one...\n\
-          Edit beforehand"
+          Edit beforehand, or run `sail init --interactive`"
     );
 }
 
-pub fn parse_conf() -> Result<Sail> {
-    let conf: Config = confy::load_path("sail.toml")?;
+/// "path:line:column" of byte `offset` of `source`, counted from 1
+fn location(origin: &str, source: &str, offset: usize) -> String {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+
+    format!("{}:{}:{}", origin, line, column)
+}
+
+/// Keys leading to an ignored field, e.g. ["users", "0", "nmae"]
+fn ignored_keys(path: &serde_ignored::Path, keys: &mut Vec<String>) {
+    match path {
+        serde_ignored::Path::Root => {}
+        serde_ignored::Path::Seq { parent, index } => {
+            ignored_keys(parent, keys);
+            keys.push(index.to_string());
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            ignored_keys(parent, keys);
+            keys.push(key.clone());
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => ignored_keys(parent, keys),
+    }
+}
+
+/// Byte range of the last of `keys` in `doc`, numbers index arrays
+fn key_span(doc: &ImDocument<&str>, keys: &[String]) -> Option<Range<usize>> {
+    let (last, parents) = keys.split_last()?;
+    let mut table: &dyn TableLike = doc.as_table();
+    let mut parents = parents.iter();
+    while let Some(key) = parents.next() {
+        table = match table.get(key)? {
+            Item::ArrayOfTables(tables) => tables.get(parents.next()?.parse().ok()?)?,
+            Item::Value(Value::Array(values)) => values
+                .get(parents.next()?.parse().ok()?)?
+                .as_inline_table()?,
+            item => item.as_table_like()?,
+        };
+    }
+
+    table.key(last)?.span()
+}
+
+/// Location of the keys of `ignored` in `source`
+fn locate_keys(source: &str, origin: &str, ignored: &[Vec<String>]) -> Result<Vec<String>> {
+    let doc = ImDocument::parse(source)?;
+
+    Ok(ignored
+        .iter()
+        .map(|keys| {
+            let at = match key_span(&doc, keys) {
+                Some(span) => location(origin, source, span.start),
+                None => origin.to_owned(),
+            };
+            format!("{}: unknown key {}", at, keys.join("."))
+        })
+        .collect())
+}
+
+/// Config in `source`, read from `origin`, and the location of every key
+/// sail doesn't know
+fn from_toml(source: &str, origin: &str) -> Result<(Config, Vec<String>)> {
+    let mut ignored = Vec::new();
+    let conf = serde_ignored::deserialize(toml::Deserializer::new(source), |path| {
+        let mut keys = Vec::new();
+        ignored_keys(&path, &mut keys);
+        ignored.push(keys);
+    })
+    .map_err(|err| {
+        let at = match err.span() {
+            Some(span) => location(origin, source, span.start),
+            None => origin.to_owned(),
+        };
+        anyhow!("{}: {}", at, err.message().trim_end())
+    })?;
+
+    Ok((conf, locate_keys(source, origin, &ignored)?))
+}
+
+/// Like `from_toml` for an already parsed `table`, errors only point at
+/// `origin`
+fn from_table(table: toml::Table, origin: &str) -> Result<(Config, Vec<String>)> {
+    let mut unknown_keys = Vec::new();
+    let conf = serde_ignored::deserialize(toml::Value::Table(table), |path| {
+        let mut keys = Vec::new();
+        ignored_keys(&path, &mut keys);
+        unknown_keys.push(format!("{}: unknown key {}", origin, keys.join(".")));
+    })
+    .map_err(|err| anyhow!("{}: {}", origin, err.message().trim_end()))?;
+
+    Ok((conf, unknown_keys))
+}
+
+/// Merge `layer` over `base`, tables are merged key by key, anything else
+/// including arrays is replaced
+fn merge(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Value at the dotted `key` of `table`
+fn lookup<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, key),
+    };
+    let table = match parents {
+        Some(parents) => lookup(table, parents)?.as_table()?,
+        None => table,
+    };
+
+    table.get(last)
+}
+
+/// Table with only `value` at the dotted `key`, e.g. "encryption.key".
+/// `value` is read as a TOML value, e.g. true or ["a", "b"], or taken as
+/// a string when it isn't one or when `current`, the value it overrides,
+/// is a string, e.g. a hostname of digits.
+fn override_table(key: &str, value: &str, current: Option<&toml::Value>) -> Result<toml::Table> {
+    if key.split('.').any(str::is_empty) {
+        bail!(r#""{}" isn't a valid key"#, key);
+    }
+
+    let parsed = format!("value = {}", value).parse::<toml::Table>();
+    let mut value = match parsed {
+        Ok(mut table) if table.len() == 1 => match table.remove("value").context("No value")? {
+            parsed if current.is_some_and(toml::Value::is_str) && !parsed.is_str() => {
+                toml::Value::String(value.to_owned())
+            }
+            parsed => parsed,
+        },
+        _ => toml::Value::String(value.to_owned()),
+    };
+    for key in key.rsplit('.') {
+        let mut table = toml::Table::new();
+        table.insert(key.to_owned(), value);
+        value = toml::Value::Table(table);
+    }
+
+    match value {
+        toml::Value::Table(table) => Ok(table),
+        _ => bail!(r#""{}" isn't a valid key"#, key),
+    }
+}
+
+/// Overrides from the SAIL_<KEY> environment variables, e.g. SAIL_HOSTNAME,
+/// "__" separates the keys of sections, e.g. SAIL_ENCRYPTION__KEY
+fn env_overrides() -> Vec<(String, String, String)> {
+    let mut overrides: Vec<_> = std::env::vars()
+        .filter_map(|(name, value)| {
+            let key = name
+                .strip_prefix("SAIL_")?
+                .to_lowercase()
+                .replace("__", ".");
+            Some((name, key, value))
+        })
+        .collect();
+    overrides.sort();
+
+    overrides
+}
+
+/// Config of the files at `paths` merged in order, then the SAIL_*
+/// environment variables, then the `sets` in the form of key=value. Keys
+/// sail doesn't know are returned with their location.
+fn load_conf(paths: &[String], sets: &[String]) -> Result<(Config, Vec<String>)> {
+    let mut merged = toml::Table::new();
+    let mut unknown_keys = Vec::new();
+
+    for path in paths {
+        let source = fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+        let (_, unknown) = from_toml(&source, path)?;
+        unknown_keys.extend(unknown);
+        merge(&mut merged, source.parse()?);
+    }
+
+    let mut overrides = env_overrides();
+    for set in sets {
+        let (key, value) = set
+            .split_once('=')
+            .with_context(|| format!(r#""{}" isn't in the form of key=value"#, set))?;
+        overrides.push((format!("--set {}", set), key.to_owned(), value.to_owned()));
+    }
+    let defaults = toml::Table::try_from(Config::default())?;
+    for (origin, key, value) in overrides {
+        let current = lookup(&merged, &key).or_else(|| lookup(&defaults, &key));
+        let layer = override_table(&key, &value, current).with_context(|| origin.clone())?;
+        let (_, unknown) = from_table(layer.clone(), &origin)?;
+        unknown_keys.extend(unknown);
+        merge(&mut merged, layer);
+    }
+
+    let (conf, _) = from_table(merged, &paths.join(" + "))?;
+
+    Ok((conf, unknown_keys))
+}
+
+/// Config like `load_conf`, warning about the unknown keys
+pub fn read_conf(paths: &[String], sets: &[String]) -> Result<Config> {
+    let (conf, unknown_keys) = load_conf(paths, sets)?;
+
+    for unknown_key in unknown_keys {
+        eprintln!("Warning: {}", unknown_key);
+    }
+
+    Ok(conf)
+}
 
-    dbg!(&conf);
-    let sail = Sail::new(
-        conf.linvar,
-        conf.zfs_type,
-        conf.storage_type,
-        conf.disk,
-        conf.partsize_esp,
-        conf.partsize_bpool,
-    )?;
+pub fn parse_conf(paths: &[String], sets: &[String], resumed: Option<Layout>) -> Result<Sail> {
+    let sail = Sail::new(read_conf(paths, sets)?, resumed)?;
 
     Ok(sail)
 }
+
+/// Run every check of `sail start` on the config merged from `paths`,
+/// without changing anything. Unknown keys fail the check too. The SAIL_*
+/// environment variables apply like they would to `sail start`, they're
+/// listed so they don't go unnoticed.
+pub fn check_conf(paths: &[String]) -> Result<()> {
+    // Values are left out, they may hold the passphrase
+    for (name, key, _) in env_overrides() {
+        eprintln!("{} from the environment overrides {}", name, key);
+    }
+    let (conf, unknown_keys) = load_conf(paths, &[])?;
+    let origin = paths.join(" + ");
+
+    for unknown_key in &unknown_keys {
+        eprintln!("{}", unknown_key);
+    }
+    let sail = Sail::new(conf, None).with_context(|| origin.clone())?;
+
+    // The local repository is only set up on the live system by `start`
+    if sail.get_repo().is_none() {
+        package::find(&mut Real::default(), sail.get_linvar(), &[])
+            .with_context(|| origin.clone())?;
+    }
+
+    if !unknown_keys.is_empty() {
+        bail!("{} has {} unknown keys", origin, unknown_keys.len());
+    }
+    eprintln!("{} is valid", origin);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sail::{EncryptionKey, Profile};
+
+    /// `conf` written by `render` and read back
+    fn round_trip(conf: &Config) -> Config {
+        let (parsed, unknown_keys) = from_toml(&render(conf), "sail.toml").unwrap();
+        assert!(unknown_keys.is_empty(), "{:?}", unknown_keys);
+
+        parsed
+    }
+
+    #[test]
+    fn default_template() {
+        let conf = round_trip(&Config::default());
+        assert_eq!(conf.disk, "");
+        assert!(conf.disks.is_empty());
+        assert_eq!(conf.topology, Topology::Single);
+        assert_eq!(conf.partsize_esp, Size::Bytes(512 << 20));
+        assert_eq!(conf.datasets.len(), Dataset::default_layout().len());
+        assert!(conf.users.is_empty());
+    }
+
+    #[test]
+    fn filled_in_template() {
+        let conf = Config {
+            linvar: LinuxVariant::LinuxLts,
+            zfs_type: ZfsType::Dkms,
+            disks: vec![
+                "/dev/disk/by-id/ata-A".to_owned(),
+                "/dev/disk/by-id/ata-B".to_owned(),
+            ],
+            topology: Topology::Mirror,
+            wipe: true,
+            hostname: "nas".to_owned(),
+            users: vec![User {
+                name: "alice".to_owned(),
+                password_hash: Some("$6$salt$\\quoted\"hash".to_owned()),
+                sudo: true,
+                ..User::default()
+            }],
+            packages: Packages {
+                profile: Profile::Server,
+                extra: vec!["htop".to_owned()],
+                exclude: Vec::new(),
+            },
+            repo: Repo {
+                path: "/srv/sail-repo".to_owned(),
+            },
+            ..Config::default()
+        };
+
+        let parsed = round_trip(&conf);
+        assert!(matches!(parsed.linvar, LinuxVariant::LinuxLts));
+        assert!(matches!(parsed.zfs_type, ZfsType::Dkms));
+        assert_eq!(parsed.disk, "");
+        assert_eq!(parsed.disks, conf.disks);
+        assert_eq!(parsed.topology, Topology::Mirror);
+        assert!(parsed.wipe);
+        assert_eq!(parsed.hostname, "nas");
+        assert_eq!(parsed.users.len(), 1);
+        assert_eq!(parsed.users[0].password_hash, conf.users[0].password_hash);
+        assert!(parsed.users[0].sudo);
+        assert_eq!(parsed.packages.profile, Profile::Server);
+        assert_eq!(parsed.packages.extra, ["htop"]);
+        assert_eq!(parsed.repo.path, "/srv/sail-repo");
+    }
+
+    #[test]
+    fn unknown_keys_are_located() {
+        let source = "\
+hostname = \"nas\"
+hostnmae = \"nas\"
+
+[encryption]
+key = \"Passphrase\"
+chiper = \"Aes256Gcm\"
+
+[[users]]
+name = \"alice\"
+
+[[users]]
+name = \"bob\"
+  sduo = true
+";
+        let (conf, unknown_keys) = from_toml(source, "sail.toml").unwrap();
+        assert_eq!(conf.hostname, "nas");
+        assert_eq!(conf.users.len(), 2);
+        assert_eq!(
+            unknown_keys,
+            [
+                "sail.toml:2:1: unknown key hostnmae",
+                "sail.toml:6:1: unknown key encryption.chiper",
+                "sail.toml:13:3: unknown key users.1.sduo",
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let err = from_toml("wipe = true\nlinvar = \"Linx\"\n", "sail.toml").unwrap_err();
+        let err = err.to_string();
+        assert!(
+            err.starts_with("sail.toml:2:10: unknown variant `Linx`"),
+            "{}",
+            err
+        );
+
+        let err = from_toml("wipe = \"yes\"\n", "sail.toml").unwrap_err();
+        assert!(
+            err.to_string().starts_with("sail.toml:1:8: invalid type"),
+            "{}",
+            err
+        );
+
+        let err = from_toml("partsize_esp = \"512X\"\n", "sail.toml").unwrap_err();
+        assert!(err.to_string().starts_with("sail.toml:1:16: "), "{}", err);
+
+        let err = from_toml("[[users]\nname = 1\n", "sail.toml").unwrap_err();
+        assert!(err.to_string().starts_with("sail.toml:1:"), "{}", err);
+    }
+
+    fn table(source: &str) -> toml::Table {
+        source.parse().unwrap()
+    }
+
+    #[test]
+    fn later_layers_override() {
+        let mut merged = table(
+            "hostname = \"base\"\nlocales = [\"en_US.UTF-8\", \"de_DE.UTF-8\"]\n\
+             [rpool_fs_properties]\ncompression = \"lz4\"\natime = \"off\"\n",
+        );
+        merge(
+            &mut merged,
+            table(
+                "hostname = \"host\"\nlocales = [\"id_ID.UTF-8\"]\n\
+                 [rpool_fs_properties]\ncompression = \"zstd\"\n",
+            ),
+        );
+        merge(
+            &mut merged,
+            override_table("hostname", "cli", None).unwrap(),
+        );
+
+        let (conf, unknown_keys) = from_table(merged, "base.toml + host.toml").unwrap();
+        assert!(unknown_keys.is_empty());
+        assert_eq!(conf.hostname, "cli");
+        assert_eq!(conf.locales, ["id_ID.UTF-8"]);
+        assert_eq!(conf.rpool_fs_properties["compression"], "zstd");
+        assert_eq!(conf.rpool_fs_properties["atime"], "off");
+    }
+
+    #[test]
+    fn override_values() {
+        let (conf, _) = from_table(override_table("wipe", "true", None).unwrap(), "--set").unwrap();
+        assert!(conf.wipe);
+
+        let disks = override_table("disks", r#"["/dev/sda", "/dev/sdb"]"#, None).unwrap();
+        let (conf, _) = from_table(disks, "--set").unwrap();
+        assert_eq!(conf.disks, ["/dev/sda", "/dev/sdb"]);
+
+        let disk = override_table("disk", "/dev/disk/by-id/ata-A", None).unwrap();
+        let (conf, _) = from_table(disk, "--set").unwrap();
+        assert_eq!(conf.disk, "/dev/disk/by-id/ata-A");
+
+        let key = override_table("encryption.key", "Passphrase", None).unwrap();
+        let (conf, _) = from_table(key, "--set").unwrap();
+        assert!(conf.encryption.passphrase.is_empty());
+        assert!(matches!(conf.encryption.key, EncryptionKey::Passphrase));
+
+        let err =
+            from_table(override_table("wipe", "yes", None).unwrap(), "SAIL_WIPE").unwrap_err();
+        assert!(
+            err.to_string().starts_with("SAIL_WIPE: invalid type"),
+            "{}",
+            err
+        );
+
+        let (_, unknown_keys) =
+            from_table(override_table("hostnmae", "x", None).unwrap(), "--set").unwrap();
+        assert_eq!(unknown_keys, ["--set: unknown key hostnmae"]);
+
+        assert!(override_table("encryption..key", "x", None).is_err());
+    }
+
+    #[test]
+    fn numeric_override_of_a_string_stays_a_string() {
+        let defaults = toml::Table::try_from(Config::default()).unwrap();
+        let current = lookup(&defaults, "hostname");
+        let hostname = override_table("hostname", "2024", current).unwrap();
+        let (conf, _) = from_table(hostname, "--set").unwrap();
+        assert_eq!(conf.hostname, "2024");
+
+        let current = lookup(&defaults, "encryption.passphrase");
+        let passphrase = override_table("encryption.passphrase", "123456", current).unwrap();
+        let (conf, _) = from_table(passphrase, "SAIL_ENCRYPTION__PASSPHRASE").unwrap();
+        assert_eq!(conf.encryption.passphrase, "123456");
+
+        // Not a string to begin with
+        let wipe = override_table("wipe", "true", lookup(&defaults, "wipe")).unwrap();
+        assert_eq!(wipe["wipe"], toml::Value::Boolean(true));
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut conf = Config {
+            root_password: RootPassword::Hashed,
+            root_password_hash: "$6$salt$root".to_owned(),
+            users: vec![
+                User {
+                    name: "alice".to_owned(),
+                    password_hash: Some("$6$salt$alice".to_owned()),
+                    ..User::default()
+                },
+                User {
+                    name: "bob".to_owned(),
+                    ..User::default()
+                },
+            ],
+            ..Config::default()
+        };
+        conf.encryption.key = EncryptionKey::Passphrase;
+        conf.encryption.passphrase = "correct horse".to_owned();
+
+        let redacted = conf.to_redacted_toml().unwrap();
+        assert!(!redacted.contains("$6$"));
+        assert!(!redacted.contains("correct horse"));
+
+        let (parsed, unknown_keys) = from_toml(&redacted, "install.toml").unwrap();
+        assert!(unknown_keys.is_empty(), "{:?}", unknown_keys);
+        assert_eq!(parsed.root_password_hash, REDACTED);
+        assert_eq!(parsed.encryption.passphrase, REDACTED);
+        assert_eq!(parsed.users[0].password_hash.as_deref(), Some(REDACTED));
+        assert_eq!(parsed.users[1].password_hash, None);
+        assert_eq!(parsed.datasets.len(), Dataset::default_layout().len());
+    }
+}
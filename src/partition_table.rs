@@ -0,0 +1,219 @@
+use anyhow::{bail, Context, Result};
+
+/// GPT of a disk, parsed from `sgdisk -p`
+#[derive(Debug, PartialEq)]
+pub struct PartitionTable {
+    sector_size: u64,
+    max_entries: usize,
+    first_usable: u64,
+    last_usable: u64,
+    alignment: u64,
+    /// Number, first and last sector of every partition
+    partitions: Vec<(usize, u64, u64)>,
+}
+
+/// Value of `line` after `prefix`, up to the first character not in a number
+fn number_after(line: &str, prefix: &str) -> Option<u64> {
+    let (_, rest) = line.split_once(prefix)?;
+    let digits: String = rest
+        .trim_start()
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+
+    digits.parse().ok()
+}
+
+impl PartitionTable {
+    pub fn parse(out: &str) -> Result<Self> {
+        let mut sector_size = None;
+        let mut max_entries = 128;
+        let mut usable = None;
+        let mut alignment = 2048;
+        let mut partitions = Vec::new();
+        let mut in_partitions = false;
+
+        for line in out.lines() {
+            let line = line.trim();
+            if in_partitions {
+                if line.is_empty() {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let partition = match fields[..] {
+                    [number, start, end, ..] => (number.parse(), start.parse(), end.parse()),
+                    _ => bail!(r#"Unexpected partition line "{}""#, line),
+                };
+                match partition {
+                    (Ok(number), Ok(start), Ok(end)) => partitions.push((number, start, end)),
+                    _ => bail!(r#"Unexpected partition line "{}""#, line),
+                }
+            } else if line.starts_with("Number") {
+                in_partitions = true;
+            } else if line.starts_with("Sector size (logical/physical):")
+                || line.starts_with("Logical sector size:")
+            {
+                sector_size = number_after(line, ":");
+            } else if line.starts_with("Partition table holds up to") {
+                let entries = number_after(line, "up to").context("Reading table size")?;
+                max_entries = entries as usize;
+            } else if line.starts_with("First usable sector is") {
+                let first = number_after(line, "First usable sector is");
+                let last = number_after(line, "last usable sector is");
+                usable = first.zip(last);
+            } else if line.starts_with("Partitions will be aligned on") {
+                alignment = number_after(line, "aligned on").unwrap_or(alignment);
+            }
+        }
+
+        let sector_size = sector_size.context("sgdisk -p doesn't show the sector size")?;
+        let (first_usable, last_usable) =
+            usable.context("sgdisk -p doesn't show the usable sectors")?;
+
+        Ok(Self {
+            sector_size,
+            max_entries,
+            first_usable,
+            last_usable,
+            alignment: alignment.max(1),
+            partitions,
+        })
+    }
+
+    /// First of `count` consecutive unused partition numbers
+    pub fn get_next_partnum(&self, count: usize) -> Result<usize> {
+        let is_free = |number: usize| self.partitions.iter().all(|(used, _, _)| *used != number);
+
+        (1..=self.max_entries + 1 - count)
+            .find(|first| (*first..first + count).all(is_free))
+            .context("Partition table is full")
+    }
+
+    /// Bytes of the largest free block, where sgdisk puts new partitions
+    pub fn get_free_space(&self) -> u64 {
+        let mut partitions: Vec<_> = self
+            .partitions
+            .iter()
+            .map(|(_, start, end)| (*start, *end))
+            .collect();
+        partitions.sort_unstable();
+
+        let mut largest = 0;
+        let mut block_start = self.first_usable;
+        let blocks_end = partitions
+            .iter()
+            .map(|(start, end)| (*start, *end + 1))
+            .chain([(self.last_usable + 1, 0)]);
+        for (next_used, next_free) in blocks_end {
+            let aligned_start = block_start.div_ceil(self.alignment) * self.alignment;
+            largest = largest.max(next_used.saturating_sub(aligned_start));
+            block_start = block_start.max(next_free);
+        }
+
+        largest * self.sector_size
+    }
+
+    /// Bytes of the whole usable area, once the disk is wiped
+    pub fn get_usable_space(&self) -> u64 {
+        let aligned_start = self.first_usable.div_ceil(self.alignment) * self.alignment;
+
+        (self.last_usable + 1).saturating_sub(aligned_start) * self.sector_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    const EMPTY: &str = "\
+Creating new GPT entries in memory.
+Disk /dev/loop0: 20971520 sectors, 10.0 GiB
+Sector size (logical/physical): 512/512 bytes
+Disk identifier (GUID): 0E5E7D4C-91C8-4D5B-9E0E-3C0B3F4A1F11
+Partition table holds up to 128 entries
+Main partition table begins at sector 2 and ends at sector 33
+First usable sector is 34, last usable sector is 20971486
+Partitions will be aligned on 2048-sector boundaries
+Total free space is 20971453 sectors (10.0 GiB)
+
+Number  Start (sector)    End (sector)  Size       Code  Name
+";
+
+    const DUAL_BOOT: &str = "\
+Disk /dev/nvme0n1: 1000215216 sectors, 476.9 GiB
+Model: Samsung SSD 970 EVO Plus 500GB
+Sector size (logical/physical): 512/512 bytes
+Disk identifier (GUID): 7B1E2A44-6A2B-4C5E-8D3F-1B2C3D4E5F60
+Partition table holds up to 128 entries
+Main partition table begins at sector 2 and ends at sector 33
+First usable sector is 34, last usable sector is 1000215182
+Partitions will be aligned on 2048-sector boundaries
+Total free space is 790499950 sectors (376.9 GiB)
+
+Number  Start (sector)    End (sector)  Size       Code  Name
+   1            2048          206847   100.0 MiB   EF00  EFI system partition
+   2          206848          239615   16.0 MiB    0C01  Microsoft reserved ...
+   3          239616       209715199   99.9 GiB    0700  Basic data partition
+";
+
+    const GAPS: &str = "\
+Disk /dev/sdb: 976773168 sectors, 465.8 GiB
+Model: WDC WD5000AAKX-0
+Sector size (logical/physical): 512/4096 bytes
+Disk identifier (GUID): 2C9D2A4E-5B6F-4A7C-9D8E-0F1A2B3C4D5E
+Partition table holds up to 128 entries
+Main partition table begins at sector 2 and ends at sector 33
+First usable sector is 34, last usable sector is 976773134
+Partitions will be aligned on 2048-sector boundaries
+Total free space is 557342653 sectors (265.8 GiB)
+
+Number  Start (sector)    End (sector)  Size       Code  Name
+   1            2048         2099199   1024.0 MiB  EF00  EFI system partition
+   4       209715200       419430399   100.0 GiB   8300  Linux filesystem
+   2         2099200        23070719   10.0 GiB    8200  Linux swap
+";
+
+    #[test]
+    fn empty_disk_starts_at_one() {
+        let table = PartitionTable::parse(EMPTY).unwrap();
+        assert_eq!(table.get_next_partnum(3).unwrap(), 1);
+        assert_eq!(table.get_free_space(), (20971486 + 1 - 2048) * 512);
+        assert_eq!(table.get_free_space(), table.get_usable_space());
+    }
+
+    #[test]
+    fn partitions_are_appended() {
+        let table = PartitionTable::parse(DUAL_BOOT).unwrap();
+        assert_eq!(table.get_next_partnum(3).unwrap(), 4);
+        assert_eq!(table.get_free_space(), (1000215182 + 1 - 209715200) * 512);
+        assert!(table.get_free_space() > 370 * GIB);
+    }
+
+    #[test]
+    fn gaps_in_numbers_and_sectors() {
+        let table = PartitionTable::parse(GAPS).unwrap();
+        // 3 is free but 4 isn't
+        assert_eq!(table.get_next_partnum(3).unwrap(), 5);
+        assert_eq!(table.get_next_partnum(1).unwrap(), 3);
+        // The block after partition 4 is larger than the one between 2 and 4
+        assert_eq!(table.get_free_space(), (976773134 + 1 - 419430400) * 512);
+    }
+
+    #[test]
+    fn full_table() {
+        let mut out = EMPTY.replace("up to 128 entries", "up to 4 entries");
+        out.push_str("   1            2048          206847   100.0 MiB   EF00  ESP\n");
+        out.push_str("   3          206848          411647   100.0 MiB   8300  Linux\n");
+        let table = PartitionTable::parse(&out).unwrap();
+        assert_eq!(table.get_next_partnum(1).unwrap(), 2);
+        assert!(table.get_next_partnum(3).is_err());
+    }
+
+    #[test]
+    fn missing_header() {
+        let out = "Number  Start (sector)    End (sector)  Size       Code  Name\n";
+        assert!(PartitionTable::parse(out).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{runner::shell_quote, setup, string_res};
+use anyhow::{bail, Context, Result};
+use std::process::Command;
+
+const USER_INPUT_SEPARATOR: &str =
+    "# ================ user input line separator ================ #";
+
+pub struct PostScript {
+    pub name: &'static str,
+    pub desc: &'static str,
+    pub script: &'static str,
+}
+
+pub const POST_SCRIPTS: [PostScript; 6] = [
+    PostScript {
+        name: "additional_storage.sh",
+        desc: "create a data pool, optionally encrypted, on another disk",
+        script: string_res::ADDITIONAL_STORAGE_S,
+    },
+    PostScript {
+        name: "add_user.sh",
+        desc: "add a wheel user with zsh as login shell",
+        script: string_res::ADD_USER_S,
+    },
+    PostScript {
+        name: "enable_services.sh",
+        desc: "enable the zrepl auto snapshotter",
+        script: string_res::ENABLE_SERVICES_S,
+    },
+    PostScript {
+        name: "nix_install.sh",
+        desc: "install nix and generate channel setup scripts for a user",
+        script: string_res::NIX_INSTALL_S,
+    },
+    PostScript {
+        name: "gnome_install.sh",
+        desc: "install GNOME and enable gdm",
+        script: string_res::GNOME_INSTALL_S,
+    },
+    PostScript {
+        name: "zfs_mount_generator.sh",
+        desc: "populate zfs-list.cache so data pools mount at boot",
+        script: string_res::ZFS_MOUNT_GENERATOR_S,
+    },
+];
+
+impl PostScript {
+    /// Names of the variables assigned above the user input separator,
+    /// arrays are left out as a quoted value can't override them
+    fn vars(&self) -> Vec<&str> {
+        let head = match self.script.split_once(USER_INPUT_SEPARATOR) {
+            Some((head, _)) => head,
+            None => return Vec::new(),
+        };
+
+        head.lines()
+            .filter_map(|line| line.split_once('='))
+            .filter(|(name, value)| {
+                !name.is_empty() && !name.contains(char::is_whitespace) && !value.starts_with('(')
+            })
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Insert `name=value` overrides right after the user input separator,
+    /// so they take precedence over the placeholders in the script. Values
+    /// are quoted, the script runs as root.
+    fn with_vars(&self, vars: &[String]) -> Result<String> {
+        if vars.is_empty() {
+            return Ok(self.script.to_owned());
+        }
+
+        let known_vars = self.vars();
+        if known_vars.is_empty() {
+            bail!("{} doesn't take any variable", self.name);
+        }
+
+        let mut overrides = String::new();
+        for var in vars {
+            let (name, value) = var
+                .split_once('=')
+                .with_context(|| format!(r#""{}" isn't in the form of name=value"#, var))?;
+            if !known_vars.contains(&name) {
+                bail!(
+                    "{} has no variable named {} (available: {})",
+                    self.name,
+                    name,
+                    known_vars.join(", ")
+                );
+            }
+            overrides.push_str(&format!("{}={}\n", name, shell_quote(value)));
+        }
+
+        let (head, body) = self
+            .script
+            .split_once(USER_INPUT_SEPARATOR)
+            .context("split script at the user input separator")?;
+
+        Ok(format!(
+            "{}{}\n{}{}",
+            head, USER_INPUT_SEPARATOR, overrides, body
+        ))
+    }
+}
+
+fn find(name: &str) -> Result<&'static PostScript> {
+    match POST_SCRIPTS
+        .iter()
+        .find(|post_script| post_script.name == name)
+    {
+        Some(post_script) => Ok(post_script),
+        None => bail!(r#""{}" not found, see `sail list`"#, name),
+    }
+}
+
+pub fn list() {
+    for post_script in &POST_SCRIPTS {
+        let vars = post_script.vars();
+        if vars.is_empty() {
+            println!("{:<24}{}", post_script.name, post_script.desc);
+        } else {
+            println!(
+                "{:<24}{} (vars: {})",
+                post_script.name,
+                post_script.desc,
+                vars.join(", ")
+            );
+        }
+    }
+}
+
+pub fn exec(name: &str, vars: &[String]) -> Result<()> {
+    let post_script = find(name)?;
+    let script = post_script.with_vars(vars)?;
+
+    setup::check_as_root()?;
+
+    eprintln!("\nExecuting {}...\n", post_script.name);
+    // Not through the runner, the script may need the terminal (e.g. passwd)
+    let status = Command::new("bash")
+        .arg("-c")
+        .arg(script)
+        .status()
+        .with_context(|| format!("Executing {}", name))?;
+    if !status.success() {
+        bail!("{} failed with {}", name, status);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vars_above_the_separator() {
+        assert_eq!(find("add_user.sh").unwrap().vars(), ["my_user"]);
+        // dsets_mpoint_pair is an array
+        assert_eq!(
+            find("additional_storage.sh").unwrap().vars(),
+            [
+                "my_user",
+                "pool_name",
+                "disk",
+                "tmp_mpoint",
+                "encryption",
+                "cipher"
+            ]
+        );
+        assert!(find("enable_services.sh").unwrap().vars().is_empty());
+    }
+
+    #[test]
+    fn vars_are_quoted_after_the_separator() {
+        let add_user = find("add_user.sh").unwrap();
+        let script = add_user
+            .with_vars(&["my_user=x; rm -rf /".to_owned()])
+            .unwrap();
+        let (_, body) = script.split_once(USER_INPUT_SEPARATOR).unwrap();
+        assert!(body.starts_with("\nmy_user='x; rm -rf /'\n"));
+
+        let script = add_user.with_vars(&["my_user=it's".to_owned()]).unwrap();
+        assert!(script.contains(r"my_user='it'\''s'"));
+
+        assert!(add_user.with_vars(&["shell=zsh".to_owned()]).is_err());
+        assert!(add_user.with_vars(&["my_user".to_owned()]).is_err());
+        assert!(find("enable_services.sh")
+            .unwrap()
+            .with_vars(&["my_user=a".to_owned()])
+            .is_err());
+    }
+}
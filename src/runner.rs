@@ -0,0 +1,767 @@
+use anyhow::{bail, Context, Error, Result};
+use chrono::Local;
+use serde_derive::Serialize;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    process::{Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Argument of the `cmd!` macro
+pub trait Arg {
+    fn push_to(self, argv: &mut Vec<String>);
+}
+
+/// Split an argument on whitespace, `%"..."` in `cmd!`
+pub struct Split<'a>(pub &'a str);
+
+impl Arg for Split<'_> {
+    fn push_to(self, argv: &mut Vec<String>) {
+        argv.extend(self.0.split_whitespace().map(str::to_owned));
+    }
+}
+
+impl Arg for &str {
+    fn push_to(self, argv: &mut Vec<String>) {
+        argv.push(self.to_owned());
+    }
+}
+
+impl Arg for String {
+    fn push_to(self, argv: &mut Vec<String>) {
+        argv.push(self);
+    }
+}
+
+impl Arg for &String {
+    fn push_to(self, argv: &mut Vec<String>) {
+        argv.push(self.clone());
+    }
+}
+
+impl<const N: usize> Arg for [&str; N] {
+    fn push_to(self, argv: &mut Vec<String>) {
+        argv.extend(self.iter().map(|arg| arg.to_string()));
+    }
+}
+
+impl Arg for Vec<String> {
+    fn push_to(self, argv: &mut Vec<String>) {
+        argv.extend(self);
+    }
+}
+
+impl Arg for &[String] {
+    fn push_to(self, argv: &mut Vec<String>) {
+        argv.extend_from_slice(self);
+    }
+}
+
+/// Build a `Cmd`, arguments prefixed with `%` are split on whitespace
+/// like in cradle
+macro_rules! cmd {
+    (@push $argv:ident;) => {};
+    (@push $argv:ident; % $arg:expr $(, $($rest:tt)*)?) => {
+        $crate::runner::Arg::push_to($crate::runner::Split($arg), &mut $argv);
+        cmd!(@push $argv; $($($rest)*)?);
+    };
+    (@push $argv:ident; $arg:expr $(, $($rest:tt)*)?) => {
+        $crate::runner::Arg::push_to($arg, &mut $argv);
+        cmd!(@push $argv; $($($rest)*)?);
+    };
+    ($($args:tt)*) => {{
+        let mut argv: Vec<String> = Vec::new();
+        cmd!(@push argv; $($args)*);
+        $crate::runner::Cmd::new(argv)
+    }};
+}
+pub(crate) use cmd;
+
+pub struct Cmd {
+    argv: Vec<String>,
+    stdin: Option<String>,
+    secret: bool,
+}
+
+impl Cmd {
+    pub fn new(argv: Vec<String>) -> Self {
+        Self {
+            argv,
+            stdin: None,
+            secret: false,
+        }
+    }
+
+    pub fn stdin(mut self, stdin: impl Into<String>) -> Self {
+        self.stdin = Some(stdin.into());
+        self
+    }
+
+    /// Like `stdin`, but `<secret>` is printed in place of `stdin`
+    pub fn secret_stdin(mut self, stdin: impl Into<String>) -> Self {
+        self.secret = true;
+        self.stdin(stdin)
+    }
+
+    pub fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    pub fn get_stdin(&self) -> Option<&str> {
+        self.stdin.as_deref()
+    }
+}
+
+/// Quote `arg` so the printed command can be pasted into a shell
+pub fn shell_quote(arg: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-+=:,./@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Heredoc with `content` as its body, `content` is printed verbatim
+fn heredoc(content: &str) -> String {
+    let mut delim = "EOF".to_owned();
+    while content.lines().any(|line| line == delim) {
+        delim.push('_');
+    }
+
+    format!("<<'{delim}'\n{}\n{delim}", content, delim = delim)
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let argv: Vec<String> = self.argv.iter().map(|arg| shell_quote(arg)).collect();
+        write!(f, "{}", argv.join(" "))?;
+        if let Some(stdin) = &self.stdin {
+            let stdin = if self.secret { "<secret>" } else { stdin };
+            write!(f, " {}", heredoc(stdin))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the commands of the setup steps
+pub trait CommandRunner {
+    /// Run `cmd`, its stdout and stderr go to the terminal
+    fn run(&mut self, cmd: &Cmd) -> Result<()>;
+
+    /// Run `cmd` and capture its trimmed stdout
+    fn output(&mut self, cmd: &Cmd) -> Result<String>;
+
+    /// Like `output` for a `cmd` that only reads the system, it's run even
+    /// in a dry run since the next commands depend on its output
+    fn query(&mut self, cmd: &Cmd) -> Result<String> {
+        self.output(cmd)
+    }
+}
+
+/// Writes the files of the setup steps
+pub trait FileWriter {
+    /// Write `content` plus a newline to `path`, truncating it
+    fn writeln_w(&mut self, content: &str, path: &str) -> Result<()>;
+
+    /// Append `content` plus a newline to `path`
+    fn writeln_a(&mut self, content: &str, path: &str) -> Result<()>;
+}
+
+/// Everything a setup step needs to act on the target system
+pub trait Executor: CommandRunner + FileWriter {
+    /// Report the progress of a setup step
+    fn log(&mut self, content: &str) {
+        eprintln!("\n{}...\n", content);
+    }
+
+    /// Register `cmd` to undo the last change if the installation fails
+    fn push_undo(&mut self, _cmd: Cmd) {}
+
+    /// Installation step `name` is about to run
+    fn begin_step(&mut self, _name: &str) {}
+
+    /// Installation step `name` ran, `error` is why it failed
+    fn end_step(&mut self, _name: &str, _error: Option<&Error>) {}
+}
+
+/// Log of the installation on the live system, it's copied to the new
+/// root before the @install snapshot
+pub const INSTALL_LOG_PATH: &str = "sail_install.log";
+
+/// Command executed by `Real`, times are milliseconds since the Unix epoch
+struct Finished {
+    start: u128,
+    end: u128,
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl Finished {
+    fn check(&self, cmd: &Cmd) -> Result<()> {
+        if !self.status.success() {
+            bail!("{} failed with {}", cmd.get_argv().join(" "), self.status);
+        }
+
+        Ok(())
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis())
+}
+
+/// Read `from` to the end in a thread, copying it to `echo` as it comes
+fn tee(
+    mut from: impl Read + Send + 'static,
+    mut echo: Option<Box<dyn Write + Send>>,
+) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut out = Vec::new();
+        let mut buf = [0; 8192];
+        while let Ok(len @ 1..) = from.read(&mut buf) {
+            if let Some(echo) = &mut echo {
+                let _ = echo.write_all(&buf[..len]);
+                let _ = echo.flush();
+            }
+            out.extend_from_slice(&buf[..len]);
+        }
+        out
+    })
+}
+
+/// Executes commands and writes files for real, and logs them with their
+/// output when made `with_log`
+#[derive(Default)]
+pub struct Real {
+    log: Option<File>,
+}
+
+impl Real {
+    /// Also log to `INSTALL_LOG_PATH`, after the log of the interrupted
+    /// installation when `resume`
+    pub fn with_log(resume: bool) -> Result<Self> {
+        let log = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .mode(0o600)
+            .open(INSTALL_LOG_PATH)
+            .with_context(|| format!("Opening {}", INSTALL_LOG_PATH))?;
+
+        Ok(Self { log: Some(log) })
+    }
+
+    /// Append `content` to the log, after the time
+    fn write_log(&mut self, content: &str) {
+        if let Some(log) = &mut self.log {
+            let time = Local::now().format("%F %T");
+            // A log that can't be written doesn't stop the installation
+            let _ = writeln!(log, "[{}] {}", time, content);
+        }
+    }
+
+    /// Append the output of a command to the log as is
+    fn write_log_output(&mut self, out: &[u8]) {
+        if let Some(log) = &mut self.log {
+            if !out.is_empty() {
+                let _ = log.write_all(out);
+                if !out.ends_with(b"\n") {
+                    let _ = writeln!(log);
+                }
+            }
+        }
+    }
+
+    /// Execute `cmd` and log it, its stdout and stderr are captured and
+    /// also copied to the terminal when `echo_stdout` and `echo_stderr`
+    fn execute(&mut self, cmd: &Cmd, echo_stdout: bool, echo_stderr: bool) -> Result<Finished> {
+        let argv = cmd.get_argv();
+        let (program, args) = argv.split_first().context("Empty command")?;
+        self.write_log(&format!("$ {}", cmd));
+        let start = now();
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Executing {}", program))?;
+        let stdin = cmd.get_stdin().unwrap_or("").to_owned();
+        let mut child_stdin = child.stdin.take().context("No stdin")?;
+        let writer = thread::spawn(move || child_stdin.write_all(stdin.as_bytes()));
+        let stdout = child.stdout.take().context("No stdout")?;
+        let stdout = tee(stdout, echo_stdout.then(|| Box::new(io::stdout()) as _));
+        let stderr = child.stderr.take().context("No stderr")?;
+        let stderr = tee(stderr, echo_stderr.then(|| Box::new(io::stderr()) as _));
+
+        let status = child.wait()?;
+        // A command may exit without reading its stdin
+        let _ = writer.join();
+        let finished = Finished {
+            start,
+            end: now(),
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        };
+
+        self.write_log_output(&finished.stdout);
+        self.write_log_output(&finished.stderr);
+        self.write_log(&finished.status.to_string());
+
+        Ok(finished)
+    }
+}
+
+impl CommandRunner for Real {
+    fn run(&mut self, cmd: &Cmd) -> Result<()> {
+        self.execute(cmd, true, true)?.check(cmd)
+    }
+
+    fn output(&mut self, cmd: &Cmd) -> Result<String> {
+        let finished = self.execute(cmd, false, true)?;
+        finished.check(cmd)?;
+
+        Ok(String::from_utf8_lossy(&finished.stdout).trim().to_owned())
+    }
+}
+
+impl FileWriter for Real {
+    fn writeln_w(&mut self, content: &str, path: &str) -> Result<()> {
+        self.write_log(&format!("cat > {} {}", shell_quote(path), heredoc(content)));
+        let mut path = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        writeln!(path, "{}", content)?;
+
+        Ok(())
+    }
+
+    fn writeln_a(&mut self, content: &str, path: &str) -> Result<()> {
+        self.write_log(&format!(
+            "cat >> {} {}",
+            shell_quote(path),
+            heredoc(content)
+        ));
+        let mut path = OpenOptions::new().append(true).create(true).open(path)?;
+        writeln!(path, "{}", content)?;
+
+        Ok(())
+    }
+}
+
+impl Executor for Real {
+    fn log(&mut self, content: &str) {
+        eprintln!("\n{}...\n", content);
+        self.write_log(&format!("# {}", content));
+    }
+}
+
+/// Prints commands and file writes as a shell script instead of executing
+/// them
+pub struct DryRun;
+
+impl CommandRunner for DryRun {
+    fn run(&mut self, cmd: &Cmd) -> Result<()> {
+        println!("{}", cmd);
+
+        Ok(())
+    }
+
+    /// `<program output>` is returned as a placeholder of the output
+    fn output(&mut self, cmd: &Cmd) -> Result<String> {
+        println!("{}", cmd);
+        let program = cmd.get_argv().first().map_or("", String::as_str);
+
+        Ok(format!("<{} output>", program))
+    }
+
+    fn query(&mut self, cmd: &Cmd) -> Result<String> {
+        println!("# {}", cmd);
+
+        Real::default().output(cmd)
+    }
+}
+
+impl FileWriter for DryRun {
+    fn writeln_w(&mut self, content: &str, path: &str) -> Result<()> {
+        println!("cat > {} {}", shell_quote(path), heredoc(content));
+
+        Ok(())
+    }
+
+    fn writeln_a(&mut self, content: &str, path: &str) -> Result<()> {
+        println!("cat >> {} {}", shell_quote(path), heredoc(content));
+
+        Ok(())
+    }
+}
+
+impl Executor for DryRun {
+    fn log(&mut self, content: &str) {
+        println!("\n# {}", content);
+    }
+}
+
+/// Bytes of stdout and stderr kept in a command event, from the end
+const JSON_OUTPUT_LIMIT: usize = 4096;
+
+/// Event printed as a line of JSON by `Json`, times are milliseconds
+/// since the Unix epoch
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    StepStart {
+        step: &'a str,
+        time: u128,
+    },
+    StepEnd {
+        step: &'a str,
+        time: u128,
+        error: Option<String>,
+    },
+    Log {
+        step: Option<&'a str>,
+        message: &'a str,
+    },
+    Command {
+        step: Option<&'a str>,
+        argv: &'a [String],
+        start: u128,
+        end: u128,
+        exit_status: Option<i32>,
+        stdout: String,
+        stderr: String,
+        truncated: bool,
+    },
+    Write {
+        step: Option<&'a str>,
+        path: &'a str,
+        append: bool,
+    },
+}
+
+/// Last `JSON_OUTPUT_LIMIT` bytes of `out`, and whether some were cut
+fn truncate_output(out: &[u8]) -> (String, bool) {
+    let out = String::from_utf8_lossy(out);
+    if out.len() <= JSON_OUTPUT_LIMIT {
+        return (out.into_owned(), false);
+    }
+
+    let mut start = out.len() - JSON_OUTPUT_LIMIT;
+    while !out.is_char_boundary(start) {
+        start += 1;
+    }
+    (out[start..].to_owned(), true)
+}
+
+/// Executes commands for real like `Real`, but prints a JSON event per
+/// line on stdout for every step, log message, command and file write
+/// instead of the output of the commands
+#[derive(Default)]
+pub struct Json {
+    real: Real,
+    step: Option<String>,
+    dry_run: bool,
+}
+
+impl Json {
+    pub fn new(real: Real) -> Self {
+        Self {
+            real,
+            ..Self::default()
+        }
+    }
+
+    /// Events of the commands and file writes like `DryRun`, without
+    /// executing them: their exit status is null and their output empty
+    pub fn dry_run() -> Self {
+        Self {
+            dry_run: true,
+            ..Self::default()
+        }
+    }
+
+    fn emit(&self, event: &JsonEvent) {
+        if let Ok(line) = serde_json::to_string(event) {
+            println!("{}", line);
+        }
+    }
+
+    /// Run `cmd` and emit its event, its trimmed stdout is returned
+    fn execute(&mut self, cmd: &Cmd) -> Result<String> {
+        if self.dry_run {
+            let time = now();
+            self.emit(&JsonEvent::Command {
+                step: self.step.as_deref(),
+                argv: cmd.get_argv(),
+                start: time,
+                end: time,
+                exit_status: None,
+                stdout: String::new(),
+                stderr: String::new(),
+                truncated: false,
+            });
+            let program = cmd.get_argv().first().map_or("", String::as_str);
+
+            return Ok(format!("<{} output>", program));
+        }
+
+        self.execute_for_real(cmd)
+    }
+
+    fn execute_for_real(&mut self, cmd: &Cmd) -> Result<String> {
+        let finished = self.real.execute(cmd, false, false)?;
+
+        let (stdout, stdout_cut) = truncate_output(&finished.stdout);
+        let (stderr, stderr_cut) = truncate_output(&finished.stderr);
+        self.emit(&JsonEvent::Command {
+            step: self.step.as_deref(),
+            argv: cmd.get_argv(),
+            start: finished.start,
+            end: finished.end,
+            exit_status: finished.status.code(),
+            stdout,
+            stderr,
+            truncated: stdout_cut || stderr_cut,
+        });
+        finished.check(cmd)?;
+
+        Ok(String::from_utf8_lossy(&finished.stdout).trim().to_owned())
+    }
+}
+
+impl CommandRunner for Json {
+    fn run(&mut self, cmd: &Cmd) -> Result<()> {
+        self.execute(cmd)?;
+
+        Ok(())
+    }
+
+    fn output(&mut self, cmd: &Cmd) -> Result<String> {
+        self.execute(cmd)
+    }
+
+    fn query(&mut self, cmd: &Cmd) -> Result<String> {
+        self.execute_for_real(cmd)
+    }
+}
+
+impl FileWriter for Json {
+    fn writeln_w(&mut self, content: &str, path: &str) -> Result<()> {
+        if !self.dry_run {
+            self.real.writeln_w(content, path)?;
+        }
+        self.emit(&JsonEvent::Write {
+            step: self.step.as_deref(),
+            path,
+            append: false,
+        });
+
+        Ok(())
+    }
+
+    fn writeln_a(&mut self, content: &str, path: &str) -> Result<()> {
+        if !self.dry_run {
+            self.real.writeln_a(content, path)?;
+        }
+        self.emit(&JsonEvent::Write {
+            step: self.step.as_deref(),
+            path,
+            append: true,
+        });
+
+        Ok(())
+    }
+}
+
+impl Executor for Json {
+    fn log(&mut self, content: &str) {
+        self.real.write_log(&format!("# {}", content));
+        self.emit(&JsonEvent::Log {
+            step: self.step.as_deref(),
+            message: content,
+        });
+    }
+
+    fn begin_step(&mut self, name: &str) {
+        self.step = Some(name.to_owned());
+        self.emit(&JsonEvent::StepStart {
+            step: name,
+            time: now(),
+        });
+    }
+
+    fn end_step(&mut self, name: &str, error: Option<&Error>) {
+        self.emit(&JsonEvent::StepEnd {
+            step: name,
+            time: now(),
+            error: error.map(|err| format!("{:#}", err)),
+        });
+        self.step = None;
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Run(Vec<String>, Option<String>),
+    Write(String, String),
+    Append(String, String),
+    Undo(Vec<String>),
+}
+
+/// Records commands and file writes for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recorder {
+    pub events: Vec<Event>,
+    outputs: Vec<(Vec<String>, String)>,
+    failures: Vec<Vec<String>>,
+}
+
+#[cfg(test)]
+impl Recorder {
+    /// Make `output` return `out` for commands starting with `argv_prefix`
+    pub fn with_output(mut self, argv_prefix: &[&str], out: &str) -> Self {
+        let argv_prefix = argv_prefix.iter().map(|arg| arg.to_string()).collect();
+        self.outputs.push((argv_prefix, out.to_owned()));
+        self
+    }
+
+    /// Make commands starting with `argv_prefix` fail, after recording them
+    pub fn with_failure(mut self, argv_prefix: &[&str]) -> Self {
+        let argv_prefix = argv_prefix.iter().map(|arg| arg.to_string()).collect();
+        self.failures.push(argv_prefix);
+        self
+    }
+
+    /// Argument vectors of the recorded commands
+    pub fn argvs(&self) -> Vec<Vec<&str>> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Run(argv, _) => Some(argv.iter().map(String::as_str).collect()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Argument vectors of the recorded commands starting with `argv_prefix`
+    pub fn argvs_of(&self, argv_prefix: &[&str]) -> Vec<Vec<&str>> {
+        self.argvs()
+            .into_iter()
+            .filter(|argv| argv.starts_with(argv_prefix))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for Recorder {
+    fn run(&mut self, cmd: &Cmd) -> Result<()> {
+        let stdin = cmd.get_stdin().map(str::to_owned);
+        self.events.push(Event::Run(cmd.get_argv().to_vec(), stdin));
+        if self
+            .failures
+            .iter()
+            .any(|argv_prefix| cmd.get_argv().starts_with(argv_prefix))
+        {
+            bail!("{} failed", cmd);
+        }
+
+        Ok(())
+    }
+
+    fn output(&mut self, cmd: &Cmd) -> Result<String> {
+        self.run(cmd)?;
+        let out = self
+            .outputs
+            .iter()
+            .find(|(argv_prefix, _)| cmd.get_argv().starts_with(argv_prefix))
+            .map(|(_, out)| out.clone())
+            .unwrap_or_default();
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+impl FileWriter for Recorder {
+    fn writeln_w(&mut self, content: &str, path: &str) -> Result<()> {
+        self.events
+            .push(Event::Write(path.to_owned(), content.to_owned()));
+
+        Ok(())
+    }
+
+    fn writeln_a(&mut self, content: &str, path: &str) -> Result<()> {
+        self.events
+            .push(Event::Append(path.to_owned(), content.to_owned()));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Executor for Recorder {
+    fn log(&mut self, _content: &str) {}
+
+    fn push_undo(&mut self, cmd: Cmd) {
+        self.events.push(Event::Undo(cmd.get_argv().to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_keeps_the_end() {
+        assert_eq!(truncate_output(b"short"), ("short".to_owned(), false));
+
+        let long = "a".repeat(JSON_OUTPUT_LIMIT) + "end";
+        let (out, truncated) = truncate_output(long.as_bytes());
+        assert!(truncated);
+        assert_eq!(out.len(), JSON_OUTPUT_LIMIT);
+        assert!(out.ends_with("aend"));
+
+        // Cut inside a multibyte character
+        let long = "é".repeat(JSON_OUTPUT_LIMIT);
+        let (out, truncated) = truncate_output(long.as_bytes());
+        assert!(truncated);
+        assert!(out.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn json_runs_commands() {
+        let mut json = Json::default();
+        let out = json
+            .output(&cmd!("sh", "-c", "cat; echo err >&2").stdin("in\n"))
+            .unwrap();
+        assert_eq!(out, "in");
+
+        assert!(json.run(&cmd!(%"sh -c", "exit 3")).is_err());
+    }
+
+    #[test]
+    fn json_dry_run_executes_only_queries() {
+        let mut json = Json::dry_run();
+        assert!(json.run(&cmd!(%"sh -c", "exit 3")).is_ok());
+        assert_eq!(json.output(&cmd!(%"echo out")).unwrap(), "<echo output>");
+        assert_eq!(json.query(&cmd!(%"echo out")).unwrap(), "out");
+        json.writeln_w("x", "/nonexistent/sail").unwrap();
+    }
+}
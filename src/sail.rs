@@ -1,110 +1,915 @@
+use crate::{
+    cache, device,
+    parse_conf::Config,
+    partition_table::PartitionTable,
+    size::{self, Size},
+};
 use anyhow::Result;
 use anyhow::{bail, Context};
-use cradle::output::Status;
+use cradle::output::{Status, StdoutTrimmed};
 use cradle::run_result;
 use serde_derive::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use std::{collections::BTreeMap, fmt, fs, path::Path};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub enum LinuxVariant {
+    #[default]
     Linux,
     LinuxLts,
     LinuxZen,
     LinuxHardened,
 }
 
-impl Default for LinuxVariant {
-    fn default() -> Self {
-        LinuxVariant::Linux
+impl LinuxVariant {
+    /// Package name of the kernel
+    pub fn get_name(&self) -> &str {
+        match self {
+            LinuxVariant::Linux => "linux",
+            LinuxVariant::LinuxLts => "linux-lts",
+            LinuxVariant::LinuxZen => "linux-zen",
+            LinuxVariant::LinuxHardened => "linux-hardened",
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub enum ZfsType {
+    #[default]
     Normal,
     Dkms,
 }
 
-impl Default for ZfsType {
-    fn default() -> Self {
-        ZfsType::Normal
+impl ZfsType {
+    /// Package name of the ZFS module for kernel `linux`
+    pub fn get_package(&self, linux: &str) -> String {
+        "zfs-".to_owned()
+            + match self {
+                ZfsType::Normal => linux,
+                ZfsType::Dkms => "dkms",
+            }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub enum StorageType {
+    #[default]
     Ssd,
     Hdd,
 }
 
-impl Default for StorageType {
+/// Vdev layout of bpool and rpool over the disks
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Topology {
+    #[default]
+    #[serde(alias = "single")]
+    Single,
+    #[serde(alias = "mirror")]
+    Mirror,
+    #[serde(alias = "raidz1")]
+    Raidz1,
+    #[serde(alias = "raidz2")]
+    Raidz2,
+    #[serde(alias = "raidz3")]
+    Raidz3,
+}
+
+impl Topology {
+    pub fn min_disks(&self) -> usize {
+        match self {
+            Topology::Single => 1,
+            Topology::Mirror | Topology::Raidz1 => 2,
+            Topology::Raidz2 => 3,
+            Topology::Raidz3 => 4,
+        }
+    }
+
+    /// Vdev type keyword of zpool create, none for a single disk
+    fn get_vdev_type(&self) -> Option<&str> {
+        match self {
+            Topology::Single => None,
+            Topology::Mirror => Some("mirror"),
+            Topology::Raidz1 => Some("raidz1"),
+            Topology::Raidz2 => Some("raidz2"),
+            Topology::Raidz3 => Some("raidz3"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub enum RootPassword {
+    #[default]
+    Prompt,
+    Hashed,
+    Locked,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub enum EncryptionKey {
+    #[default]
+    None,
+    Passphrase,
+    Keyfile,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub enum Cipher {
+    #[default]
+    Aes256Gcm,
+    Aes192Gcm,
+    Aes128Gcm,
+    Aes256Ccm,
+    Aes192Ccm,
+    Aes128Ccm,
+}
+
+impl Cipher {
+    fn get_name(&self) -> &str {
+        match self {
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::Aes192Gcm => "aes-192-gcm",
+            Cipher::Aes128Gcm => "aes-128-gcm",
+            Cipher::Aes256Ccm => "aes-256-ccm",
+            Cipher::Aes192Ccm => "aes-192-ccm",
+            Cipher::Aes128Ccm => "aes-128-ccm",
+        }
+    }
+}
+
+/// ZFS native encryption of rpool
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Encryption {
+    pub key: EncryptionKey,
+    pub cipher: Cipher,
+    /// Used with `key = "Passphrase"`, prompted when empty
+    pub passphrase: String,
+    /// Used with `key = "Keyfile"`, 32 random bytes, e.g. from
+    /// `dd if=/dev/urandom of=rpool.key bs=32 count=1`
+    pub keyfile: String,
+    /// Required with `key = "Keyfile"`: the keyfile is copied into the
+    /// initramfs to unlock rpool at boot, and the initramfs sits unencrypted
+    /// on bpool
+    pub embed_keyfile: bool,
+}
+
+/// The passphrase is left out, a debug print of the config can end up in
+/// the install log or the JSON output
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let passphrase = if self.passphrase.is_empty() {
+            ""
+        } else {
+            "<redacted>"
+        };
+
+        f.debug_struct("Encryption")
+            .field("key", &self.key)
+            .field("cipher", &self.cipher)
+            .field("passphrase", &passphrase)
+            .field("keyfile", &self.keyfile)
+            .field("embed_keyfile", &self.embed_keyfile)
+            .finish()
+    }
+}
+
+/// Named selection of the packages `pacstrap` installs
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Profile {
+    #[serde(alias = "minimal")]
+    Minimal,
+    #[default]
+    #[serde(alias = "desktop")]
+    Desktop,
+    #[serde(alias = "server")]
+    Server,
+}
+
+/// Packages the installation relies on, in every profile
+const REQUIRED_PACKAGES: [&str; 8] = [
+    "base",
+    "base-devel",
+    "dosfstools",
+    "git",
+    "grub",
+    "mkinitcpio",
+    "networkmanager",
+    "sudo",
+];
+
+pub const MICROCODE_PACKAGES: [&str; 2] = ["intel-ucode", "amd-ucode"];
+
+impl Profile {
+    /// Packages of the profile besides `REQUIRED_PACKAGES`
+    fn get_packages(&self) -> &[&str] {
+        match self {
+            Profile::Minimal => &["nano", "zsh"],
+            Profile::Desktop => &["mandoc", "nano", "neovim", "os-prober", "reflector", "zsh"],
+            Profile::Server => &["mandoc", "nano", "neovim", "reflector", "zsh"],
+        }
+    }
+}
+
+/// Packages installed besides the kernel and zfs
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Packages {
+    pub profile: Profile,
+    /// Installed after zfs, e.g. `extra = ["htop", "openssh"]`
+    pub extra: Vec<String>,
+    /// Left out of the profile or the firmware, e.g. `exclude = ["neovim"]`
+    pub exclude: Vec<String>,
+}
+
+impl Packages {
+    /// Packages of the profile and the firmware with `microcode`, less the
+    /// excluded ones, then the extra ones
+    pub fn select(&self, microcode: &[&str]) -> Result<PackageSet> {
+        let profile = self.profile.get_packages();
+        for name in &self.exclude {
+            if REQUIRED_PACKAGES.contains(&name.as_str()) {
+                bail!(
+                    "{} is needed by the installation and can't be excluded",
+                    name
+                );
+            }
+            let is_known = profile
+                .iter()
+                .chain(&["linux-firmware"])
+                .chain(&MICROCODE_PACKAGES)
+                .any(|known| known == name);
+            if !is_known {
+                bail!(
+                    "{} of exclude isn't installed by the {:?} profile",
+                    name,
+                    self.profile
+                );
+            }
+            if self.extra.contains(name) {
+                bail!("{} is in both extra and exclude", name);
+            }
+        }
+
+        let keep = |names: &[&str]| -> Vec<String> {
+            names
+                .iter()
+                .filter(|name| !self.exclude.iter().any(|excluded| excluded == *name))
+                .map(|name| name.to_string())
+                .collect()
+        };
+        let base = keep(&[&REQUIRED_PACKAGES[..], profile].concat());
+        let firmware = keep(&[&["linux-firmware"][..], microcode].concat());
+
+        let mut extra = Vec::new();
+        for name in &self.extra {
+            if name.is_empty() || name.starts_with('-') || name.contains(char::is_whitespace) {
+                bail!(r#""{}" of extra isn't a valid package name"#, name);
+            }
+            if !base.contains(name) && !firmware.contains(name) && !extra.contains(name) {
+                extra.push(name.clone());
+            }
+        }
+
+        Ok(PackageSet {
+            base,
+            firmware,
+            extra,
+        })
+    }
+}
+
+/// `[packages]` resolved to what `pacstrap` installs
+pub struct PackageSet {
+    base: Vec<String>,
+    firmware: Vec<String>,
+    extra: Vec<String>,
+}
+
+impl PackageSet {
+    pub fn get_base(&self) -> &[String] {
+        &self.base
+    }
+
+    /// linux-firmware and the microcode
+    pub fn get_firmware(&self) -> &[String] {
+        &self.firmware
+    }
+
+    pub fn get_extra(&self) -> &[String] {
+        &self.extra
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        [&self.base, &self.firmware, &self.extra]
+            .iter()
+            .any(|names| names.iter().any(|known| known == name))
+    }
+}
+
+/// Microcode package of the CPU vendor in `cpuinfo`, of /proc/cpuinfo, both
+/// when the vendor is unknown
+pub fn microcode_of(cpuinfo: &str) -> Vec<&'static str> {
+    let vendor = cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "vendor_id").then(|| value.trim())
+    });
+
+    match vendor {
+        Some("GenuineIntel") => vec!["intel-ucode"],
+        Some("AuthenticAMD") => vec!["amd-ucode"],
+        _ => MICROCODE_PACKAGES.to_vec(),
+    }
+}
+
+/// Local pacman repository to install from without internet access
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Repo {
+    /// Directory made by `sail cache build`, the network is used when it's
+    /// empty
+    pub path: String,
+}
+
+/// `[repo]` checked to have every file of `sail cache build`
+pub struct LocalRepo {
+    path: String,
+    keyid: String,
+    archzfs_keyid: String,
+}
+
+impl LocalRepo {
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    /// Key `sail cache build` signs the repository with
+    pub fn get_keyid(&self) -> &str {
+        &self.keyid
+    }
+
+    /// Key archzfs signs its packages with
+    pub fn get_archzfs_keyid(&self) -> &str {
+        &self.archzfs_keyid
+    }
+
+    /// Path of `name` in the repository
+    pub fn get_file(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CanMount {
+    #[default]
+    #[serde(alias = "on")]
+    On,
+    #[serde(alias = "off")]
+    Off,
+    #[serde(alias = "noauto")]
+    Noauto,
+}
+
+/// Dataset of rpool/arch/DATA/default, shared by every boot environment
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Dataset {
+    /// Path below rpool/arch/DATA/default, e.g. "var/lib/docker", its
+    /// parent has to be listed before it
+    pub name: String,
+    pub canmount: CanMount,
+    /// Inherited from the parent when missing, i.e. /<name>
+    pub mountpoint: Option<String>,
+    /// Other zfs properties, e.g. `properties = { recordsize = "16k" }`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+    /// Mode of the mounted dataset after its creation, e.g. "750"
+    pub chmod: Option<String>,
+}
+
+impl Dataset {
+    fn new(name: &str, canmount: CanMount, chmod: Option<&str>) -> Self {
+        Self {
+            name: name.to_owned(),
+            canmount,
+            chmod: chmod.map(str::to_owned),
+            ..Self::default()
+        }
+    }
+
+    /// Layout used when sail.toml doesn't have any `[[datasets]]`
+    pub fn default_layout() -> Vec<Self> {
+        use CanMount::{Off, On};
+
+        vec![
+            Self::new("usr", Off, None),
+            Self::new("var", Off, None),
+            Self::new("var/lib", Off, None),
+            Self::new("home", On, None),
+            Self::new("root", On, Some("750")),
+            Self::new("srv", On, None),
+            Self::new("usr/local", On, None),
+            Self::new("var/log", On, None),
+            Self::new("var/spool", On, None),
+            Self::new("var/games", On, Some("775")),
+            Self::new("var/www", On, None),
+            // For GNOME
+            Self::new("var/lib/AccountsService", On, Some("775")),
+            Self::new("var/lib/docker", On, None),
+            Self::new("var/lib/nfs", On, None),
+            Self::new("var/lib/lxc", On, None),
+            Self::new("var/lib/libvirt", On, None),
+            Self::new("nix", On, None),
+        ]
+    }
+
+    /// Options of zfs create
+    pub fn get_options(&self) -> Vec<String> {
+        let canmount = match self.canmount {
+            CanMount::On => "on",
+            CanMount::Off => "off",
+            CanMount::Noauto => "noauto",
+        };
+
+        let mut options = vec!["-o".to_owned(), format!("canmount={}", canmount)];
+        if let Some(mountpoint) = &self.mountpoint {
+            options.extend(["-o".to_owned(), format!("mountpoint={}", mountpoint)]);
+        }
+        for (prop, value) in &self.properties {
+            options.extend(["-o".to_owned(), format!("{}={}", prop, value)]);
+        }
+
+        options
+    }
+
+    /// Where the dataset is mounted in the new system
+    pub fn get_mountpoint(&self) -> String {
+        match &self.mountpoint {
+            Some(mountpoint) => mountpoint.clone(),
+            None => format!("/{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct User {
+    pub name: String,
+    /// Supplementary groups, `wheel` is added when `sudo` is set
+    pub groups: Vec<String>,
+    pub shell: String,
+    /// crypt(3) hash, e.g. from `openssl passwd -6`, the account is locked
+    /// when it's missing
+    pub password_hash: Option<String>,
+    pub ssh_authorized_keys: Vec<String>,
+    pub sudo: bool,
+}
+
+impl Default for User {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            groups: Vec::new(),
+            shell: "/bin/zsh".to_owned(),
+            password_hash: None,
+            ssh_authorized_keys: Vec::new(),
+            sudo: false,
+        }
+    }
+}
+
+impl User {
+    /// Groups to pass to useradd -G
+    pub fn get_groups(&self) -> Vec<String> {
+        let mut groups = self.groups.clone();
+        if self.sudo && !groups.iter().any(|group| group == "wheel") {
+            groups.push("wheel".to_owned());
+        }
+
+        groups
+    }
+}
+
+/// Smallest rpool partition left after esp and bpool
+const MIN_RPOOL_SIZE: u64 = 4 << 30;
+
+/// Disks and partitions of an installation, kept in the install state so
+/// `start --resume` doesn't probe the disks again: sail's partitions fill
+/// them by then
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layout {
+    disks: Vec<String>,
+    next_partnums: Vec<usize>,
+    partsize_esp: Size,
+    partsize_bpool: Size,
+}
+
+// confy loads the install state into a default one
+impl Default for Layout {
     fn default() -> Self {
-        StorageType::Ssd
+        Self {
+            disks: Vec::new(),
+            next_partnums: Vec::new(),
+            partsize_esp: Size::Bytes(0),
+            partsize_bpool: Size::Bytes(0),
+        }
+    }
+}
+
+impl Layout {
+    /// Disks of the layout, which must be `paths` of the config
+    fn into_disks(self, paths: &[String]) -> Result<Vec<Disk>> {
+        let paths: Vec<_> = paths
+            .iter()
+            .map(|path| device::resolve_by_id(path))
+            .collect::<Result<_>>()?;
+        if paths != self.disks {
+            bail!(
+                "Installation to resume was on {}, but sail.toml points to {}",
+                self.disks.join(" "),
+                paths.join(" ")
+            );
+        }
+
+        let disks = self
+            .disks
+            .into_iter()
+            .zip(self.next_partnums)
+            .map(|(path, next_partnum)| Disk {
+                path,
+                next_partnum,
+                free_space: 0,
+            })
+            .collect();
+
+        Ok(disks)
+    }
+}
+
+/// Disk of the pools, partitioned identically to the others
+pub struct Disk {
+    path: String,
+    next_partnum: usize,
+    /// Bytes of the free block the partitions go to
+    free_space: u64,
+}
+
+impl Disk {
+    /// A wiped disk is partitioned from scratch
+    fn new(path: String, wipe: bool) -> Result<Self> {
+        let block_test = "test -b ".to_owned() + &path;
+        let Status(block_status) = run_result!(%"bash -c", block_test)?;
+        if !block_status.success() {
+            bail!("{} is not a block device!", &path);
+        }
+        let path = device::resolve_by_id(&path)?;
+
+        let StdoutTrimmed(table) = run_result!(%"sgdisk -p", path.as_str())
+            .with_context(|| format!("Printing partition table of {}", path))?;
+        let table = PartitionTable::parse(&table)
+            .with_context(|| format!("Reading partition table of {}", path))?;
+        let (next_partnum, free_space) = if wipe {
+            (1, table.get_usable_space())
+        } else {
+            (table.get_next_partnum(3)?, table.get_free_space())
+        };
+
+        Ok(Self {
+            path,
+            next_partnum,
+            free_space,
+        })
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_efi_part(&self) -> String {
+        device::partition_path(&self.path, self.next_partnum)
+    }
+
+    pub fn get_bpool_part(&self) -> String {
+        device::partition_path(&self.path, self.next_partnum + 1)
+    }
+
+    pub fn get_rpool_part(&self) -> String {
+        device::partition_path(&self.path, self.next_partnum + 2)
+    }
+
+    /// Partitions created by `partition_disk`
+    pub fn get_new_parts(&self) -> [String; 3] {
+        [
+            self.get_efi_part(),
+            self.get_bpool_part(),
+            self.get_rpool_part(),
+        ]
+    }
+
+    pub fn get_efi_last_path(&self) -> Result<String> {
+        let efi_part = self.get_efi_part();
+        let mut suffix = efi_part.split('/');
+        let suffix = suffix
+            .next_back()
+            .context("split efi path to get the last part")?;
+
+        Ok(suffix.to_owned())
+    }
+
+    pub fn get_next_partnum(&self) -> usize {
+        self.next_partnum
+    }
+
+    pub fn get_free_space(&self) -> u64 {
+        self.free_space
+    }
+}
+
+/// Name and zpool create options of a pool
+pub struct Pool {
+    name: String,
+    properties: Vec<(String, String)>,
+    fs_properties: Vec<(String, String)>,
+}
+
+impl Pool {
+    fn new(name: &str, properties: &[(&str, &str)], fs_properties: &[(&str, &str)]) -> Self {
+        let to_owned = |props: &[(&str, &str)]| {
+            props
+                .iter()
+                .map(|(prop, value)| (prop.to_string(), value.to_string()))
+                .collect()
+        };
+
+        Self {
+            name: name.to_owned(),
+            properties: to_owned(properties),
+            fs_properties: to_owned(fs_properties),
+        }
+    }
+
+    fn bpool(name: &str) -> Self {
+        Self::new(
+            name,
+            &[
+                ("compatibility", "grub2"),
+                ("ashift", "12"),
+                ("autotrim", "on"),
+            ],
+            &[
+                ("acltype", "posixacl"),
+                ("canmount", "off"),
+                ("compression", "lz4"),
+                ("devices", "off"),
+                ("normalization", "formD"),
+                ("relatime", "on"),
+                ("xattr", "sa"),
+                ("mountpoint", "/boot"),
+            ],
+        )
+    }
+
+    fn rpool(name: &str) -> Self {
+        Self::new(
+            name,
+            &[("ashift", "12"), ("autotrim", "on")],
+            &[
+                ("acltype", "posixacl"),
+                ("canmount", "off"),
+                ("compression", "zstd"),
+                ("dnodesize", "auto"),
+                ("normalization", "formD"),
+                ("relatime", "on"),
+                ("xattr", "sa"),
+                ("mountpoint", "/"),
+            ],
+        )
+    }
+
+    /// Merge the properties of sail.toml over the defaults
+    fn with_overrides(
+        mut self,
+        properties: BTreeMap<String, String>,
+        fs_properties: BTreeMap<String, String>,
+    ) -> Result<Self> {
+        let merge = |props: &mut Vec<(String, String)>, overrides: BTreeMap<String, String>| {
+            for (prop, value) in overrides {
+                match props.iter_mut().find(|(known, _)| *known == prop) {
+                    Some((_, known_value)) => *known_value = value,
+                    None => props.push((prop, value)),
+                }
+            }
+        };
+
+        // The installer relies on these, rpool encryption is set by [encryption]
+        let reserved = [
+            "mountpoint",
+            "canmount",
+            "encryption",
+            "keyformat",
+            "keylocation",
+        ];
+        if let Some(prop) = fs_properties
+            .keys()
+            .find(|prop| reserved.contains(&prop.as_str()))
+        {
+            bail!("{} of {} can't be changed", prop, self.name);
+        }
+        if let Some(prop) = properties
+            .keys()
+            .find(|prop| ["altroot", "cachefile"].contains(&prop.as_str()))
+        {
+            bail!("{} of {} can't be changed", prop, self.name);
+        }
+
+        merge(&mut self.properties, properties);
+        merge(&mut self.fs_properties, fs_properties);
+
+        Ok(self)
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// -o options of zpool create
+    pub fn get_properties(&self) -> Vec<String> {
+        Self::to_options("-o", &self.properties)
+    }
+
+    /// -O options of zpool create
+    pub fn get_fs_properties(&self) -> Vec<String> {
+        Self::to_options("-O", &self.fs_properties)
+    }
+
+    fn to_options(flag: &str, props: &[(String, String)]) -> Vec<String> {
+        props
+            .iter()
+            .flat_map(|(prop, value)| [flag.to_owned(), format!("{}={}", prop, value)])
+            .collect()
     }
 }
 
 pub struct Sail {
     inst_linvar: String,
     inst_zfs: String,
-    disk: String,
-    inst_partsize_esp: String,
-    inst_partsize_bpool: String,
-    next_partnum: usize,
+    disks: Vec<Disk>,
+    topology: Topology,
+    wipe: bool,
+    inst_partsize_esp: Size,
+    inst_partsize_bpool: Size,
+    bpool: Pool,
+    rpool: Pool,
     storage_type: StorageType,
+    hostname: String,
+    timezone: String,
+    locales: Vec<String>,
+    locale_gen: Vec<String>,
+    keymap: String,
+    root_shell: String,
+    root_password: RootPassword,
+    root_password_hash: Option<String>,
+    users: Vec<User>,
+    encryption: Encryption,
+    datasets: Vec<Dataset>,
+    /// sail.toml the installation is made from, secrets redacted
+    effective_conf: String,
+    repo: Option<LocalRepo>,
+    packages: PackageSet,
 }
 
 impl Sail {
-    pub fn new(
-        linvar: LinuxVariant,
-        zfs_type: ZfsType,
-        storage_type: StorageType,
-        disk: String,
-        partsize_esp: String,
-        partsize_bpool: String,
-    ) -> Result<Self> {
-        let linvar = match linvar {
-            LinuxVariant::Linux => "linux",
-            LinuxVariant::LinuxLts => "linux-lts",
-            LinuxVariant::LinuxZen => "linux-zen",
-            LinuxVariant::LinuxHardened => "linux-hardened",
+    /// Checked `conf`, on the disks and partitions of `resumed` if an
+    /// installation is resumed
+    pub fn new(conf: Config, resumed: Option<Layout>) -> Result<Self> {
+        let effective_conf = conf.to_redacted_toml()?;
+        let Config {
+            linvar,
+            zfs_type,
+            storage_type,
+            disk,
+            disks,
+            topology,
+            wipe,
+            partsize_esp,
+            partsize_bpool,
+            bpool_name,
+            rpool_name,
+            hostname,
+            timezone,
+            locales,
+            keymap,
+            root_shell,
+            root_password,
+            root_password_hash,
+            users,
+            bpool_properties,
+            bpool_fs_properties,
+            rpool_properties,
+            rpool_fs_properties,
+            encryption,
+            datasets,
+            repo,
+            packages,
+        } = conf;
+
+        let linvar = linvar.get_name();
+        let inst_zfs = zfs_type.get_package(linvar);
+
+        let disks = match (disk.is_empty(), disks.is_empty()) {
+            (false, true) => vec![disk],
+            (true, false) => disks,
+            (true, true) => bail!("No disk to install to, set disk or disks"),
+            (false, false) => bail!("Set either disk or disks, not both"),
+        };
+        check_topology(&topology, &disks)?;
+        let (disks, partsize_esp, partsize_bpool) = match resumed {
+            Some(layout) => {
+                let (esp, bpool) = (layout.partsize_esp, layout.partsize_bpool);
+                (layout.into_disks(&disks)?, esp, bpool)
+            }
+            None => {
+                let disks: Vec<_> = disks
+                    .into_iter()
+                    .map(|disk| Disk::new(disk, wipe))
+                    .collect::<Result<_>>()?;
+                let (esp, bpool) = fit_partsizes(&disks, partsize_esp, partsize_bpool)?;
+                (disks, esp, bpool)
+            }
         };
 
-        let inst_zfs = "zfs-".to_owned()
-            + match zfs_type {
-                ZfsType::Normal => linvar,
-                ZfsType::Dkms => "dkms",
-            };
+        check_pool_names(&bpool_name, &rpool_name)?;
+        let bpool =
+            Pool::bpool(&bpool_name).with_overrides(bpool_properties, bpool_fs_properties)?;
+        let rpool =
+            Pool::rpool(&rpool_name).with_overrides(rpool_properties, rpool_fs_properties)?;
 
-        let block_test = "test -b ".to_owned() + &disk;
-        let Status(block_status) = run_result!(%"bash -c", block_test)?;
-        if !block_status.success() {
-            bail!("{} is not a block device!", &disk);
-        }
-
-        for partsize in [&partsize_esp, &partsize_bpool] {
-            let mut partsize_c = partsize.clone();
-            if let Some(unit) = partsize_c.pop() {
-                match unit {
-                    'K' | 'M' | 'G' | 'T' | 'P' => {}
-                    _ => bail!(
-                        r#""{}" isn't a valid unit in partsize_* (K, M, G, T, P)"#,
-                        partsize
-                    ),
+        check_hostname(&hostname)?;
+        check_timezone(&timezone)?;
+        check_keymap(&keymap)?;
+        if locales.is_empty() {
+            bail!("At least one locale is required in locales");
+        }
+        let locale_gen = locales
+            .iter()
+            .map(|locale| locale_gen_line(locale))
+            .collect::<Result<_>>()?;
+        if !root_shell.starts_with('/') {
+            bail!(r#"root_shell "{}" must be an absolute path"#, root_shell);
+        }
+
+        let root_password_hash = match root_password {
+            RootPassword::Hashed => {
+                if !root_password_hash.starts_with('$') {
+                    bail!(
+                        "root_password_hash must be a crypt(3) hash, e.g. from `openssl passwd -6`"
+                    );
                 }
+                Some(root_password_hash)
+            }
+            RootPassword::Prompt | RootPassword::Locked => None,
+        };
+
+        check_users(&users)?;
+        check_encryption(&encryption)?;
+        check_datasets(&datasets)?;
+        if !users.is_empty() && !datasets.iter().any(|dataset| dataset.name == "home") {
+            bail!("A home dataset is required to create users");
+        }
+        let repo = check_repo(repo)?;
+
+        let cpuinfo = fs::read_to_string("/proc/cpuinfo").context("Reading /proc/cpuinfo")?;
+        let packages = packages.select(&microcode_of(&cpuinfo))?;
+        if !packages.contains("zsh") {
+            let mut shells = users.iter().map(|user| &user.shell).chain([&root_shell]);
+            if let Some(shell) = shells.find(|shell| shell.ends_with("/zsh")) {
+                bail!("{} is a login shell, zsh can't be excluded", shell);
             }
-            partsize_c
-                .parse::<usize>()
-                .context("Invalid partsize_* size")?;
         }
 
         Ok(Self {
             inst_linvar: linvar.to_owned(),
             inst_zfs,
-            disk: disk.to_owned(),
-            inst_partsize_esp: partsize_esp.to_owned(),
-            inst_partsize_bpool: partsize_bpool.to_owned(),
-            next_partnum: Self::_get_next_partnum(&disk)?,
+            disks,
+            topology,
+            wipe,
+            inst_partsize_esp: partsize_esp,
+            inst_partsize_bpool: partsize_bpool,
+            bpool,
+            rpool,
             storage_type,
+            hostname,
+            timezone,
+            locales,
+            locale_gen,
+            keymap,
+            root_shell,
+            root_password,
+            root_password_hash,
+            users,
+            encryption,
+            datasets,
+            effective_conf,
+            repo,
+            packages,
         })
     }
 
@@ -116,89 +921,220 @@ impl Sail {
         &self.inst_zfs
     }
 
-    pub fn get_disk(&self) -> &str {
-        &self.disk
+    pub fn get_disks(&self) -> &[Disk] {
+        &self.disks
     }
 
-    pub fn get_partsize_esp(&self) -> &str {
-        &self.inst_partsize_esp
+    /// Whether the disks are wiped before partitioning
+    pub fn is_wiping(&self) -> bool {
+        self.wipe
     }
 
-    pub fn get_partsize_bpool(&self) -> &str {
-        &self.inst_partsize_bpool
+    /// Disk whose esp is mounted on /boot/efi
+    pub fn get_boot_disk(&self) -> &Disk {
+        &self.disks[0]
     }
 
-    pub fn get_efi_part(&self) -> Result<String> {
-        let efi_part = format!("{}-part{}", self.disk, self.get_next_partnum());
+    pub fn get_bpool(&self) -> &Pool {
+        &self.bpool
+    }
 
-        Ok(efi_part)
+    pub fn get_rpool(&self) -> &Pool {
+        &self.rpool
     }
 
-    pub fn get_bpool_part(&self) -> Result<String> {
-        let bpool_part = format!("{}-part{}", self.disk, self.get_next_partnum() + 1);
+    pub fn get_bpool_vdev(&self) -> Vec<String> {
+        self.get_vdev(Disk::get_bpool_part)
+    }
 
-        Ok(bpool_part)
+    pub fn get_rpool_vdev(&self) -> Vec<String> {
+        self.get_vdev(Disk::get_rpool_part)
     }
 
-    pub fn get_rpool_part(&self) -> Result<String> {
-        let rpool_part = format!("{}-part{}", self.disk, self.get_next_partnum() + 2);
+    /// Vdev arguments of zpool create with the `part` of every disk
+    fn get_vdev(&self, part: fn(&Disk) -> String) -> Vec<String> {
+        let vdev_type = self.topology.get_vdev_type().map(str::to_owned);
 
-        Ok(rpool_part)
+        vdev_type
+            .into_iter()
+            .chain(self.disks.iter().map(part))
+            .collect()
     }
 
-    pub fn get_efi_last_path(&self) -> Result<String> {
-        let efi_part = self.get_efi_part()?;
-        let suffix = efi_part.split('/');
-        let suffix = suffix
-            .last()
-            .context("split efi path to get the last part")?;
+    pub fn get_partsize_esp(&self) -> Size {
+        self.inst_partsize_esp
+    }
 
-        Ok(suffix.to_owned())
+    pub fn get_partsize_bpool(&self) -> Size {
+        self.inst_partsize_bpool
     }
 
-    pub fn get_disk_last_path(&self) -> Result<&str> {
-        let suffix = self.disk.split('/');
-        let suffix = suffix
-            .last()
-            .context("split disk path to get the last part")?;
+    pub fn get_hostname(&self) -> &str {
+        &self.hostname
+    }
 
-        Ok(suffix)
+    pub fn get_timezone(&self) -> &str {
+        &self.timezone
     }
 
-    pub fn get_next_partnum(&self) -> usize {
-        self.next_partnum
+    /// System locale, the first of `locales`
+    pub fn get_locale(&self) -> &str {
+        &self.locales[0]
     }
 
-    fn _get_next_partnum(disk: &str) -> Result<usize> {
-        let disk_path = Path::new(disk);
-        let disk_parent = disk_path
-            .parent()
-            .context("get the parent directory of $disk")?;
-        let suffix = disk.split('/');
-        let disk_last_path = suffix
-            .last()
-            .context("split disk path to get the last part")?;
-
-        let mut last_partnum = 0;
-
-        if disk_parent.is_dir() {
-            for dev in fs::read_dir(disk_parent)? {
-                let dev = dev?.file_name();
-                let dev = if let Ok(dev) = dev.into_string() {
-                    dev
-                } else {
-                    bail!("not a valid unicode!");
-                };
-
-                if dev.contains(disk_last_path) {
-                    last_partnum += 1;
-                }
+    /// Lines of /etc/locale.gen, e.g. "en_US.UTF-8 UTF-8"
+    pub fn get_locale_gen(&self) -> &[String] {
+        &self.locale_gen
+    }
+
+    pub fn get_keymap(&self) -> &str {
+        &self.keymap
+    }
+
+    pub fn get_root_shell(&self) -> &str {
+        &self.root_shell
+    }
+
+    /// Hash of the root password, `None` if the root account is locked
+    pub fn get_root_password_hash(&self) -> Option<&str> {
+        self.root_password_hash.as_deref()
+    }
+
+    pub fn is_root_locked(&self) -> bool {
+        matches!(self.root_password, RootPassword::Locked)
+    }
+
+    /// Ask for the root password with `root_password = "Prompt"`, only its
+    /// hash is kept. In dry-run mode a placeholder is used instead.
+    pub fn prompt_root_password(&mut self, dry_run: bool) -> Result<()> {
+        if !matches!(self.root_password, RootPassword::Prompt) {
+            return Ok(());
+        }
+
+        if dry_run {
+            self.root_password_hash = Some("<hash of the prompted password>".to_owned());
+            return Ok(());
+        }
+
+        loop {
+            let password = rpassword::prompt_password("Root password: ")?;
+            if password.is_empty() {
+                eprintln!("Empty password, try again");
+                continue;
             }
-        } else {
-            bail!("invalid parent disk directory!");
+
+            let confirmation = rpassword::prompt_password("Retype root password: ")?;
+            if password != confirmation {
+                eprintln!("Passwords don't match, try again");
+                continue;
+            }
+
+            let hash = pwhash::sha512_crypt::hash(password).context("Hashing root password")?;
+            self.root_password_hash = Some(hash);
+            return Ok(());
         }
+    }
 
-        Ok(last_partnum)
+    pub fn is_encrypted(&self) -> bool {
+        !matches!(self.encryption.key, EncryptionKey::None)
+    }
+
+    /// Encryption properties of rpool for zpool create
+    pub fn get_rpool_encryption(&self) -> Vec<String> {
+        let keyformat = match self.encryption.key {
+            EncryptionKey::None => return Vec::new(),
+            EncryptionKey::Passphrase => "keyformat=passphrase".to_owned(),
+            EncryptionKey::Keyfile => "keyformat=raw".to_owned(),
+        };
+        let keylocation = match self.get_keyfile() {
+            Some(keyfile) => format!("keylocation=file://{}", keyfile),
+            None => "keylocation=prompt".to_owned(),
+        };
+        let encryption = format!("encryption={}", self.encryption.cipher.get_name());
+
+        [encryption, keyformat, keylocation]
+            .into_iter()
+            .flat_map(|prop| ["-O".to_owned(), prop])
+            .collect()
+    }
+
+    /// Passphrase of rpool, fed to zfs on stdin
+    pub fn get_passphrase(&self) -> Option<&str> {
+        match self.encryption.key {
+            EncryptionKey::Passphrase => Some(&self.encryption.passphrase),
+            EncryptionKey::None | EncryptionKey::Keyfile => None,
+        }
+    }
+
+    /// Keyfile of rpool on the live system
+    pub fn get_keyfile(&self) -> Option<&str> {
+        match self.encryption.key {
+            EncryptionKey::Keyfile => Some(&self.encryption.keyfile),
+            EncryptionKey::None | EncryptionKey::Passphrase => None,
+        }
+    }
+
+    /// Ask for the passphrase of rpool when sail.toml doesn't set it
+    pub fn prompt_passphrase(&mut self, dry_run: bool) -> Result<()> {
+        if !matches!(self.encryption.key, EncryptionKey::Passphrase)
+            || !self.encryption.passphrase.is_empty()
+        {
+            return Ok(());
+        }
+
+        if dry_run {
+            self.encryption.passphrase = "<prompted passphrase>".to_owned();
+            return Ok(());
+        }
+
+        loop {
+            let prompt = format!("Passphrase of {}: ", self.rpool.name);
+            let passphrase = rpassword::prompt_password(prompt)?;
+            if passphrase.len() < 8 {
+                eprintln!("Passphrase must be at least 8 characters, try again");
+                continue;
+            }
+
+            let prompt = format!("Retype passphrase of {}: ", self.rpool.name);
+            let confirmation = rpassword::prompt_password(prompt)?;
+            if passphrase != confirmation {
+                eprintln!("Passphrases don't match, try again");
+                continue;
+            }
+
+            self.encryption.passphrase = passphrase;
+            return Ok(());
+        }
+    }
+
+    /// Local repository to install from, `None` to use the network
+    pub fn get_repo(&self) -> Option<&LocalRepo> {
+        self.repo.as_ref()
+    }
+
+    pub fn get_packages(&self) -> &PackageSet {
+        &self.packages
+    }
+
+    pub fn get_effective_conf(&self) -> &str {
+        &self.effective_conf
+    }
+
+    pub fn get_datasets(&self) -> &[Dataset] {
+        &self.datasets
+    }
+
+    pub fn get_users(&self) -> &[User] {
+        &self.users
+    }
+
+    pub fn get_layout(&self) -> Layout {
+        Layout {
+            disks: self.disks.iter().map(|disk| disk.path.clone()).collect(),
+            next_partnums: self.disks.iter().map(|disk| disk.next_partnum).collect(),
+            partsize_esp: self.inst_partsize_esp,
+            partsize_bpool: self.inst_partsize_bpool,
+        }
     }
 
     pub fn is_using_ssd(&self) -> bool {
@@ -208,3 +1144,599 @@ impl Sail {
         }
     }
 }
+
+/// Resolve the partition sizes against the smallest free space so every
+/// disk is partitioned identically, and leave room for rpool
+fn fit_partsizes(disks: &[Disk], esp: Size, bpool: Size) -> Result<(Size, Size)> {
+    let smallest = disks
+        .iter()
+        .min_by_key(|disk| disk.get_free_space())
+        .context("No disk to install to")?;
+    let free_space = smallest.get_free_space();
+
+    let esp = esp.resolve(free_space);
+    let bpool = bpool.resolve(free_space);
+    let needed = esp + bpool + MIN_RPOOL_SIZE;
+    if needed > free_space {
+        bail!(
+            "esp ({}) + bpool ({}) + rpool (at least {}) don't fit in the {} free on {}",
+            size::human(esp),
+            size::human(bpool),
+            size::human(MIN_RPOOL_SIZE),
+            size::human(free_space),
+            smallest.get_path()
+        );
+    }
+
+    Ok((Size::Bytes(esp), Size::Bytes(bpool)))
+}
+
+fn check_topology(topology: &Topology, disks: &[String]) -> Result<()> {
+    if *topology == Topology::Single && disks.len() > 1 {
+        bail!(
+            "Single topology takes one disk, use Mirror or Raidz* for {} disks",
+            disks.len()
+        );
+    }
+    if disks.len() < topology.min_disks() {
+        bail!(
+            "{:?} topology needs at least {} disks",
+            topology,
+            topology.min_disks()
+        );
+    }
+    for (i, disk) in disks.iter().enumerate() {
+        if disks[..i].contains(disk) {
+            bail!("Disk {} is listed more than once", disk);
+        }
+    }
+
+    Ok(())
+}
+
+/// Rules of `zpool create`: a letter first, then letters, digits and
+/// "_-.:". "log" and the names starting with mirror, raidz, draid or spare
+/// are reserved for vdevs, a "c" and a digit first looks like a disk.
+/// zpool takes spaces too, sail doesn't as its scripts don't quote pool
+/// names.
+fn check_pool_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let first = chars.next();
+    let is_valid = first.is_some_and(|c| c.is_ascii_alphabetic())
+        && name.len() < 256
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c));
+    if !is_valid {
+        bail!(r#""{}" isn't a valid pool name"#, name);
+    }
+
+    let is_reserved = name == "log"
+        || ["mirror", "raidz", "draid", "spare"]
+            .iter()
+            .any(|vdev| name.starts_with(vdev));
+    if is_reserved {
+        bail!(r#"Pool name "{}" is reserved by zpool"#, name);
+    }
+    if first == Some('c') && chars.next().is_some_and(|c| c.is_ascii_digit()) {
+        bail!(r#"Pool name "{}" looks like a disk name to zpool"#, name);
+    }
+
+    Ok(())
+}
+
+fn check_pool_names(bpool: &str, rpool: &str) -> Result<()> {
+    check_pool_name(bpool)?;
+    check_pool_name(rpool)?;
+    if bpool == rpool {
+        bail!("bpool_name and rpool_name must differ");
+    }
+
+    Ok(())
+}
+
+pub fn check_hostname(hostname: &str) -> Result<()> {
+    let is_valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+
+    if hostname.len() > 64 || !hostname.split('.').all(is_valid_label) {
+        bail!(r#""{}" isn't a valid hostname"#, hostname);
+    }
+
+    Ok(())
+}
+
+/// Same rule as useradd's default NAME_REGEX
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first_valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_');
+
+    first_valid
+        && name.len() <= 32
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+pub fn check_users(users: &[User]) -> Result<()> {
+    for (i, user) in users.iter().enumerate() {
+        if !is_valid_name(&user.name) || user.name == "root" {
+            bail!(r#""{}" isn't a valid user name"#, user.name);
+        }
+        if users[..i].iter().any(|other| other.name == user.name) {
+            bail!("User {} is defined more than once", user.name);
+        }
+        if let Some(group) = user.groups.iter().find(|group| !is_valid_name(group)) {
+            bail!(
+                r#""{}" of user {} isn't a valid group name"#,
+                group,
+                user.name
+            );
+        }
+        if !user.shell.starts_with('/') {
+            bail!("Shell of user {} must be an absolute path", user.name);
+        }
+        if let Some(hash) = &user.password_hash {
+            if !hash.starts_with('$') {
+                bail!(
+                    "password_hash of user {} must be a crypt(3) hash",
+                    user.name
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_datasets(datasets: &[Dataset]) -> Result<()> {
+    let is_valid_component = |component: &str| {
+        !component.is_empty()
+            && component != "."
+            && component != ".."
+            && component
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+    };
+
+    for (i, dataset) in datasets.iter().enumerate() {
+        let name = &dataset.name;
+        if !name.split('/').all(is_valid_component) {
+            bail!(r#""{}" isn't a valid dataset name"#, name);
+        }
+        let earlier = &datasets[..i];
+        if earlier.iter().any(|other| other.name == *name) {
+            bail!("Dataset {} is defined more than once", name);
+        }
+        if let Some((parent, _)) = name.rsplit_once('/') {
+            if !earlier.iter().any(|other| other.name == parent) {
+                bail!("Dataset {} has to be listed before {}", parent, name);
+            }
+        }
+        if let Some(mountpoint) = &dataset.mountpoint {
+            if !mountpoint.starts_with('/') {
+                bail!("Mountpoint of dataset {} must be an absolute path", name);
+            }
+        }
+        if let Some(mode) = &dataset.chmod {
+            let is_octal =
+                (3..=4).contains(&mode.len()) && mode.chars().all(|c| matches!(c, '0'..='7'));
+            if !is_octal {
+                bail!(r#""{}" of dataset {} isn't an octal mode"#, mode, name);
+            }
+            if dataset.canmount != CanMount::On {
+                bail!("chmod of dataset {} needs canmount = \"On\"", name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_encryption(encryption: &Encryption) -> Result<()> {
+    match encryption.key {
+        EncryptionKey::None => {}
+        EncryptionKey::Passphrase => {
+            let len = encryption.passphrase.len();
+            if len > 0 && len < 8 {
+                bail!("Encryption passphrase must be at least 8 characters");
+            }
+        }
+        EncryptionKey::Keyfile => {
+            if !encryption.keyfile.starts_with('/') {
+                bail!("Encryption keyfile must be an absolute path");
+            }
+            let keyfile = fs::metadata(&encryption.keyfile)
+                .with_context(|| format!("Reading keyfile {}", encryption.keyfile))?;
+            if keyfile.len() != 32 {
+                bail!(
+                    "Keyfile {} must be 32 bytes long, e.g. from \
+                     `dd if=/dev/urandom of=rpool.key bs=32 count=1`",
+                    encryption.keyfile
+                );
+            }
+            if !encryption.embed_keyfile {
+                bail!(
+                    "Keyfile encryption copies {} into the initramfs on the unencrypted bpool, \
+                     anyone with the disk can unlock rpool with it\n\
+                     Set encryption.embed_keyfile = true to accept this, \
+                     or use key = \"Passphrase\" instead",
+                    encryption.keyfile
+                );
+            }
+            eprintln!(
+                "Warning: the keyfile is embedded in the initramfs on the unencrypted bpool, \
+                 rpool is only as safe as the disk itself"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn check_repo(repo: Repo) -> Result<Option<LocalRepo>> {
+    if repo.path.is_empty() {
+        return Ok(None);
+    }
+    if !repo.path.starts_with('/') {
+        bail!("Repository path {} must be an absolute path", repo.path);
+    }
+    let path = repo.path.trim_end_matches('/');
+
+    for name in cache::REPO_FILES {
+        if !Path::new(path).join(name).is_file() {
+            bail!(
+                "{} has no {}, make the repository with `sail cache build {}`",
+                path,
+                name,
+                path
+            );
+        }
+    }
+    let read_keyid = |name| {
+        let keyid_path = Path::new(path).join(name);
+        fs::read_to_string(&keyid_path)
+            .map(|keyid| keyid.trim().to_owned())
+            .with_context(|| format!("Reading {}", keyid_path.display()))
+    };
+
+    Ok(Some(LocalRepo {
+        path: path.to_owned(),
+        keyid: read_keyid(cache::REPO_KEYID)?,
+        archzfs_keyid: read_keyid(cache::ARCHZFS_KEYID)?,
+    }))
+}
+
+pub fn check_timezone(timezone: &str) -> Result<()> {
+    let zoneinfo = Path::new("/usr/share/zoneinfo");
+    let tz_path = zoneinfo.join(timezone);
+    if timezone.is_empty() || timezone.contains("..") || !tz_path.is_file() {
+        bail!(
+            r#""{}" isn't a valid timezone, see {}"#,
+            timezone,
+            zoneinfo.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn find_keymap(dir: &Path, keymap: &str) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if find_keymap(&path, keymap)? {
+                return Ok(true);
+            }
+        } else if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            if name == format!("{}.map", keymap) || name == format!("{}.map.gz", keymap) {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+fn check_keymap(keymap: &str) -> Result<()> {
+    let keymaps = Path::new("/usr/share/kbd/keymaps");
+    let found = find_keymap(keymaps, keymap)
+        .with_context(|| format!("Reading keymaps in {}", keymaps.display()))?;
+    if !found {
+        bail!(
+            r#""{}" isn't a known keymap, see `localectl list-keymaps`"#,
+            keymap
+        );
+    }
+
+    Ok(())
+}
+
+/// Line of /usr/share/i18n/SUPPORTED for `locale`, e.g. "en_US.UTF-8 UTF-8"
+fn locale_gen_line(locale: &str) -> Result<String> {
+    let supported = "/usr/share/i18n/SUPPORTED";
+    let supported_c =
+        fs::read_to_string(supported).with_context(|| format!("Reading {}", supported))?;
+
+    let line = supported_c
+        .lines()
+        .find(|line| line.split_whitespace().next() == Some(locale));
+    match line {
+        Some(line) => Ok(line.trim().to_owned()),
+        None => bail!(
+            r#""{}" isn't a supported locale, see {}"#,
+            locale,
+            supported
+        ),
+    }
+}
+
+#[cfg(test)]
+impl Sail {
+    /// `Sail` on `disk` without probing the block device
+    pub fn for_test(disk: &str, next_partnum: usize) -> Self {
+        Self {
+            inst_linvar: "linux".to_owned(),
+            inst_zfs: "zfs-linux".to_owned(),
+            disks: vec![Disk {
+                path: disk.to_owned(),
+                next_partnum,
+                free_space: 64 << 30,
+            }],
+            topology: Topology::Single,
+            wipe: false,
+            inst_partsize_esp: Size::Bytes(512 << 20),
+            inst_partsize_bpool: Size::Bytes(4 << 30),
+            bpool: Pool::bpool("bpool"),
+            rpool: Pool::rpool("rpool"),
+            storage_type: StorageType::Ssd,
+            hostname: "lbox".to_owned(),
+            timezone: "Asia/Jakarta".to_owned(),
+            locales: vec!["en_US.UTF-8".to_owned()],
+            locale_gen: vec!["en_US.UTF-8 UTF-8".to_owned()],
+            keymap: "us".to_owned(),
+            root_shell: "/bin/zsh".to_owned(),
+            root_password: RootPassword::Hashed,
+            root_password_hash: Some("$6$salt$hash".to_owned()),
+            users: Vec::new(),
+            encryption: Encryption::default(),
+            datasets: Dataset::default_layout(),
+            effective_conf: "hostname = \"lbox\"\n".to_owned(),
+            repo: None,
+            packages: Packages::default().select(&["intel-ucode"]).unwrap(),
+        }
+    }
+
+    /// Same `Sail` installing `packages`
+    pub fn with_packages(mut self, packages: Packages) -> Self {
+        self.packages = packages.select(&["intel-ucode"]).unwrap();
+        self
+    }
+
+    /// Same `Sail` installing offline from the repository at `path`
+    pub fn with_repo(mut self, path: &str) -> Self {
+        self.repo = Some(LocalRepo {
+            path: path.to_owned(),
+            keyid: "0D4F5D5C1B3F6A8A2C6E1E8E0B7C6D5A4F3E2D1C".to_owned(),
+            archzfs_keyid: "DDF7DB817396A49B2A2723F7403BD972F75D9D76".to_owned(),
+        });
+        self
+    }
+
+    /// Same `Sail` with its disks wiped before partitioning
+    pub fn with_wipe(mut self) -> Self {
+        self.wipe = true;
+        for disk in &mut self.disks {
+            disk.next_partnum = 1;
+        }
+        self
+    }
+
+    /// Same `Sail` with the pools named `bpool` and `rpool`
+    pub fn with_pool_names(mut self, bpool: &str, rpool: &str) -> Self {
+        self.bpool = Pool::bpool(bpool);
+        self.rpool = Pool::rpool(rpool);
+        self
+    }
+
+    /// Same `Sail` with `datasets` instead of the default layout
+    pub fn with_datasets(mut self, datasets: Vec<Dataset>) -> Self {
+        self.datasets = datasets;
+        self
+    }
+
+    /// Same `Sail` with rpool encrypted
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Same `Sail` with the pools over `disks` in `topology`
+    pub fn with_disks(mut self, disks: &[&str], topology: Topology) -> Self {
+        let next_partnum = self.get_boot_disk().next_partnum;
+        self.disks = disks
+            .iter()
+            .map(|disk| Disk {
+                path: disk.to_string(),
+                next_partnum,
+                free_space: 64 << 30,
+            })
+            .collect();
+        self.topology = topology;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk(path: &str, free_space: u64) -> Disk {
+        Disk {
+            path: path.to_owned(),
+            next_partnum: 1,
+            free_space,
+        }
+    }
+
+    #[test]
+    fn partsizes_fit_the_smallest_disk() {
+        let disks = [disk("/dev/sda", 100 << 30), disk("/dev/sdb", 20 << 30)];
+        let (esp, bpool) =
+            fit_partsizes(&disks, Size::Bytes(1 << 30), Size::Percent(10.0)).unwrap();
+        assert_eq!(esp, Size::Bytes(1 << 30));
+        assert_eq!(bpool, Size::Bytes(2 << 30));
+
+        let err = fit_partsizes(&disks, Size::Bytes(8 << 30), Size::Bytes(10 << 30)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "esp (8.0G) + bpool (10.0G) + rpool (at least 4.0G) don't fit in the 20.0G free on /dev/sdb"
+        );
+    }
+
+    #[test]
+    fn resume_after_partition_disk() {
+        // /dev/null isn't a block device, so nothing is probed
+        let layout = Layout {
+            disks: vec!["/dev/null".to_owned()],
+            next_partnums: vec![4],
+            partsize_esp: Size::Bytes(1 << 30),
+            partsize_bpool: Size::Bytes(2 << 30),
+        };
+        let disks = layout
+            .clone()
+            .into_disks(&["/dev/null".to_owned()])
+            .unwrap();
+        assert_eq!(disks[0].get_next_partnum(), 4);
+        assert_eq!(disks[0].get_rpool_part(), "/dev/null6");
+
+        let err = layout.into_disks(&["/dev/zero".to_owned()]).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Installation to resume was on /dev/null, but sail.toml points to /dev/zero"
+        );
+    }
+
+    #[test]
+    fn keyfile_is_only_embedded_on_request() {
+        let keyfile = std::env::temp_dir().join("sail-test-rpool.key");
+        fs::write(&keyfile, [0; 32]).unwrap();
+        let mut encryption = Encryption {
+            key: EncryptionKey::Keyfile,
+            keyfile: keyfile.to_string_lossy().into_owned(),
+            ..Encryption::default()
+        };
+        let err = check_encryption(&encryption).unwrap_err();
+        assert!(err.to_string().contains("encryption.embed_keyfile = true"));
+
+        encryption.embed_keyfile = true;
+        check_encryption(&encryption).unwrap();
+        fs::remove_file(keyfile).unwrap();
+    }
+
+    #[test]
+    fn pool_names_follow_zpool() {
+        for name in [
+            "bpool",
+            "rpool",
+            "tank0",
+            "Pool_1.a:b-c",
+            "logs",
+            "c",
+            "cache",
+        ] {
+            check_pool_name(name).unwrap();
+        }
+        for name in ["", "0pool", "-pool", "my pool", "pool/a", "pool@a"] {
+            assert_eq!(
+                check_pool_name(name).unwrap_err().to_string(),
+                format!(r#""{}" isn't a valid pool name"#, name)
+            );
+        }
+        for name in ["log", "mirror", "mirror0", "raidz2pool", "draid", "spares"] {
+            assert_eq!(
+                check_pool_name(name).unwrap_err().to_string(),
+                format!(r#"Pool name "{}" is reserved by zpool"#, name)
+            );
+        }
+        assert!(check_pool_name("c0t0d0").is_err());
+        assert!(check_pool_names("rpool", "rpool").is_err());
+    }
+
+    #[test]
+    fn pool_properties_merge_over_the_defaults() {
+        let props = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(prop, value)| (prop.to_string(), value.to_string()))
+                .collect()
+        };
+        let rpool = Pool::rpool("rpool")
+            .with_overrides(
+                props(&[("ashift", "13")]),
+                props(&[("compression", "lz4"), ("recordsize", "1M")]),
+            )
+            .unwrap();
+        assert_eq!(
+            rpool.get_properties(),
+            ["-o", "ashift=13", "-o", "autotrim=on"]
+        );
+        let fs_properties = rpool.get_fs_properties();
+        assert!(fs_properties.contains(&"compression=lz4".to_owned()));
+        assert!(!fs_properties.contains(&"compression=zstd".to_owned()));
+        // New ones go last, the defaults keep their place
+        assert_eq!(fs_properties[fs_properties.len() - 1], "recordsize=1M");
+        assert_eq!(fs_properties[1], "acltype=posixacl");
+
+        let err = Pool::bpool("bpool")
+            .with_overrides(props(&[]), props(&[("mountpoint", "/boot2")]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "mountpoint of bpool can't be changed");
+        assert!(Pool::rpool("rpool")
+            .with_overrides(props(&[("altroot", "/a")]), props(&[]))
+            .is_err());
+    }
+
+    #[test]
+    fn microcode_of_cpu_vendor() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: AuthenticAMD\ncpu family\t: 25\n";
+        assert_eq!(microcode_of(cpuinfo), ["amd-ucode"]);
+        let cpuinfo = cpuinfo.replace("AuthenticAMD", "GenuineIntel");
+        assert_eq!(microcode_of(&cpuinfo), ["intel-ucode"]);
+        assert_eq!(microcode_of("processor\t: 0\n"), MICROCODE_PACKAGES);
+    }
+
+    #[test]
+    fn package_selection() {
+        let packages = Packages {
+            profile: Profile::Server,
+            extra: vec!["htop".to_owned(), "nano".to_owned(), "htop".to_owned()],
+            exclude: vec!["neovim".to_owned(), "linux-firmware".to_owned()],
+        };
+        let set = packages.select(&["amd-ucode"]).unwrap();
+        assert!(set.get_base().iter().any(|name| name == "networkmanager"));
+        assert!(!set.contains("neovim"));
+        assert!(!set.contains("os-prober"));
+        assert_eq!(set.get_firmware(), ["amd-ucode"]);
+        assert_eq!(set.get_extra(), ["htop"]);
+
+        for exclude in ["grub", "os-prober", "htop"] {
+            let packages = Packages {
+                profile: Profile::Server,
+                extra: vec!["htop".to_owned()],
+                exclude: vec![exclude.to_owned()],
+            };
+            assert!(
+                packages.select(&["amd-ucode"]).is_err(),
+                "{} was excluded",
+                exclude
+            );
+        }
+    }
+}
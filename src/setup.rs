@@ -1,29 +1,15 @@
-use crate::{sail::Sail, string_res};
-use anyhow::{bail, Context, Result};
-use cradle::{
-    input::{Split, Stdin},
-    output::{Status, StdoutTrimmed},
-    run_output, run_result,
+use crate::{
+    cache,
+    package::{self, KernelSource},
+    post_scripts,
+    runner::{cmd, Cmd, Executor, INSTALL_LOG_PATH},
+    sail::{LocalRepo, Sail, User},
+    string_res,
 };
-use std::{fs::OpenOptions, io::Write, thread, time, env};
-
-fn writeln_w(content: &str, path: &str) -> Result<()> {
-    let mut path = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path)?;
-    writeln!(path, "{}", content)?;
-
-    Ok(())
-}
-
-fn writeln_a(content: &str, path: &str) -> Result<()> {
-    let mut path = OpenOptions::new().append(true).create(true).open(path)?;
-    writeln!(path, "{}", content)?;
-
-    Ok(())
-}
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use cradle::{output::StdoutTrimmed, run_output};
+use std::{env, io};
 
 pub fn check_as_root() -> Result<()> {
     let StdoutTrimmed(uid) = run_output!(%"id -u");
@@ -35,25 +21,31 @@ pub fn check_as_root() -> Result<()> {
     Ok(())
 }
 
-pub fn init_check() -> Result<()> {
+pub fn init_check(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
     // command checker
     let commands = [
         "arch-chroot",
         "awk",
         "bash",
+        "blkdiscard",
         "blkid",
+        "bsdtar",
         "chmod",
         "curl",
         "genfstab",
         "grep",
         "hwclock",
         "id",
+        "install",
+        "lsblk",
         "mkdir",
         "mkfs.vfat",
         "modprobe",
         "mount",
+        "mountpoint",
         "mv",
         "pacman",
+        "pacman-key",
         "pacstrap",
         "partprobe",
         "rm",
@@ -61,463 +53,1450 @@ pub fn init_check() -> Result<()> {
         "sgdisk",
         "systemctl",
         "systemd-firstboot",
+        "udevadm",
         "umount",
+        "wipefs",
         "zfs",
         "zgenhostid",
         "zpool",
     ];
 
     for cmd in commands {
-        let StdoutTrimmed(_) = run_result!("which", cmd)?;
+        ex.output(&cmd!("which", cmd))?;
     }
 
-    log("Check internet connection");
-    let (Status(exit_status), StdoutTrimmed(_)) =
-        run_output!(%"curl -s --connect-timeout 3 http://google.com");
-    if !exit_status.success() {
+    if let Some(repo) = sail.get_repo() {
+        ex.log(&format!("Install offline from {}", repo.get_path()));
+        return Ok(());
+    }
+
+    ex.log("Check internet connection");
+    if ex
+        .output(&cmd!(%"curl -s --connect-timeout 3 http://google.com"))
+        .is_err()
+    {
         bail!("Connection error! Check your connection...");
     }
 
     Ok(())
 }
 
-fn log(content: &str) {
-    eprintln!("\n{}...\n", content);
+fn arch_chroot(script: &str) -> Cmd {
+    cmd!(%"arch-chroot /mnt bash --login").stdin(script)
+}
+
+/// Ask before wiping the disks, showing their model and serial so the
+/// right disks are erased
+pub fn confirm_wipe(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    eprintln!("\nEverything on these disks will be erased:");
+    for disk in sail.get_disks() {
+        let path = disk.get_path();
+        let model = ex.output(&cmd!(%"lsblk -dno MODEL", path))?;
+        let serial = ex.output(&cmd!(%"lsblk -dno SERIAL", path))?;
+        eprintln!("  {} (model: {}, serial: {})", path, model, serial);
+    }
+
+    eprint!("Type \"wipe\" to continue: ");
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Reading confirmation")?;
+    if answer.trim() != "wipe" {
+        bail!("Wipe not confirmed, nothing was changed");
+    }
+
+    Ok(())
 }
 
-pub fn partition_disk(sail: &Sail) -> Result<()> {
-    let some_delay = time::Duration::from_secs(5);
+fn wipe_disk(sail: &Sail, path: &str, ex: &mut dyn Executor) -> Result<()> {
+    ex.log(&format!("Wipe {}", path));
+    let devs = ex.output(&cmd!(%"lsblk -lnpo NAME", path))?;
+    // Partitions first, their labels are gone with the partition table
+    for dev in devs.lines().rev() {
+        // Fails when there is no zfs label
+        if ex.run(&cmd!(%"zpool labelclear -f", dev)).is_err() {
+            eprintln!("No zfs label on {}", dev);
+        }
+        ex.run(&cmd!(%"wipefs -a", dev))?;
+    }
+    ex.run(&cmd!(%"sgdisk --zap-all", path))?;
+
+    if sail.is_using_ssd() {
+        ex.log(&format!("Discard {}", path));
+        ex.run(&cmd!(%"blkdiscard -f", path))?;
+    }
+
+    Ok(())
+}
 
-    let disk = sail.get_disk();
+pub fn partition_disk(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
     let partsize_esp = sail.get_partsize_esp();
     let partsize_bpool = sail.get_partsize_bpool();
 
-    log("Find last partition number");
-    let mut next_partnum = sail.get_next_partnum();
-
-    log("Create efi partition");
-    let efi_partnum = next_partnum.to_string();
-    let part_desc = format!("-n{}:0:+{}", efi_partnum, partsize_esp);
-    let part_type = format!("-t{}:EF00", efi_partnum);
-    run_result!("sgdisk", part_desc, part_type, disk)?;
-
-    log("Create bpool partition");
-    next_partnum += 1;
-    let bpool_partnum = next_partnum.to_string();
-    let part_desc = format!("-n{}:0:+{}", bpool_partnum, partsize_bpool);
-    let part_type = format!("-t{}:BE00", bpool_partnum);
-    run_result!(%"sgdisk", part_desc, part_type, disk)?;
-
-    log("Create rpool partition");
-    next_partnum += 1;
-    let rpool_partnum = next_partnum.to_string();
-    let part_desc = format!("-n{}:0:0", rpool_partnum);
-    let part_type = format!("-t{}:BF00", rpool_partnum);
-    run_result!(%"sgdisk", part_desc, part_type, disk)?;
-
-    log("Resync partition table");
-    run_result!("partprobe")?;
-
-    thread::sleep(some_delay);
+    if sail.is_wiping() {
+        for disk in sail.get_disks() {
+            wipe_disk(sail, disk.get_path(), ex)?;
+        }
+        ex.run(&cmd!("partprobe"))?;
+    }
+
+    ex.push_undo(cmd!("partprobe"));
+
+    for disk in sail.get_disks() {
+        let path = disk.get_path();
+        let mut next_partnum = disk.get_next_partnum();
+
+        ex.log(&format!("Create efi partition on {}", path));
+        let efi_partnum = next_partnum.to_string();
+        let part_desc = format!("-n{}:0:+{}", efi_partnum, partsize_esp);
+        let part_type = format!("-t{}:EF00", efi_partnum);
+        ex.run(&cmd!("sgdisk", part_desc, part_type, path))?;
+        ex.push_undo(cmd!("sgdisk", format!("-d{}", efi_partnum), path));
+
+        ex.log(&format!("Create bpool partition on {}", path));
+        next_partnum += 1;
+        let bpool_partnum = next_partnum.to_string();
+        let part_desc = format!("-n{}:0:+{}", bpool_partnum, partsize_bpool);
+        let part_type = format!("-t{}:BE00", bpool_partnum);
+        ex.run(&cmd!(%"sgdisk", part_desc, part_type, path))?;
+        ex.push_undo(cmd!("sgdisk", format!("-d{}", bpool_partnum), path));
+
+        ex.log(&format!("Create rpool partition on {}", path));
+        next_partnum += 1;
+        let rpool_partnum = next_partnum.to_string();
+        let part_desc = format!("-n{}:0:0", rpool_partnum);
+        let part_type = format!("-t{}:BF00", rpool_partnum);
+        ex.run(&cmd!(%"sgdisk", part_desc, part_type, path))?;
+        ex.push_undo(cmd!("sgdisk", format!("-d{}", rpool_partnum), path));
+    }
+
+    ex.log("Resync partition table");
+    ex.run(&cmd!("partprobe"))?;
+
+    ex.log("Wait for the partition devices");
+    let parts: Vec<String> = sail
+        .get_disks()
+        .iter()
+        .flat_map(|disk| disk.get_new_parts())
+        .collect();
+    ex.run(&cmd!(%"udevadm wait --settle --timeout=30", parts))?;
+
     Ok(())
 }
 
-pub fn format_disk(sail: &Sail) -> Result<()> {
-    let efi_part = sail.get_efi_part()?;
-    let bpool_part = sail.get_bpool_part()?;
-    let rpool_part = sail.get_rpool_part()?;
+pub fn format_disk(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    ex.log("Load zfs kernel module");
+    ex.run(&cmd!(%"modprobe zfs"))?;
 
-    log("Load zfs kernel module");
-    run_result!(%"modprobe zfs")?;
+    let bpool = sail.get_bpool().get_name();
+    let rpool = sail.get_rpool().get_name();
 
-    log("Create boot pool");
-    run_result!(%"zpool create",
+    ex.log("Create boot pool");
+    ex.run(&cmd!(%"zpool create",
         "-f",
-        %"-o compatibility=grub2",
-        %"-o ashift=12",
-        %"-o autotrim=on",
-        %"-O acltype=posixacl",
-        %"-O canmount=off",
-        %"-O compression=lz4",
-        %"-O devices=off",
-        %"-O normalization=formD",
-        %"-O relatime=on",
-        %"-O xattr=sa",
-        %"-O mountpoint=/boot",
+        sail.get_bpool().get_properties(),
+        sail.get_bpool().get_fs_properties(),
         %"-R /mnt",
-        "bpool",
-        bpool_part)?;
+        bpool,
+        sail.get_bpool_vdev()))?;
+    ex.push_undo(cmd!(%"zpool destroy -f", bpool));
 
-    log("Create root pool");
-    run_result!(%"zpool create",
+    ex.log("Create root pool");
+    let mut create_rpool = cmd!(%"zpool create",
         "-f",
-        %"-o ashift=12",
-        %"-o autotrim=on",
+        sail.get_rpool().get_properties(),
         %"-R /mnt",
-        %"-O acltype=posixacl",
-        %"-O canmount=off",
-        %"-O compression=zstd",
-        %"-O dnodesize=auto",
-        %"-O normalization=formD",
-        %"-O relatime=on",
-        %"-O xattr=sa",
-        %"-O mountpoint=/",
-        %"rpool",
-        rpool_part)?;
-
-    log("Create root dataset");
-    run_result!(%"zfs create -o canmount=off -o mountpoint=none rpool/arch")?;
-
-    log("Create other dataset");
-    run_result!(%"zfs create -o canmount=off -o mountpoint=none bpool/arch")?;
-    run_result!(%"zfs create -o canmount=off -o mountpoint=none bpool/arch/BOOT")?;
-    run_result!(%"zfs create -o canmount=off -o mountpoint=none rpool/arch/ROOT")?;
-    run_result!(%"zfs create -o canmount=off -o mountpoint=none rpool/arch/DATA")?;
-    run_result!(%"zfs create -o mountpoint=/boot -o canmount=noauto bpool/arch/BOOT/default")?;
-    run_result!(%"zfs create -o mountpoint=/ -o canmount=off    rpool/arch/DATA/default")?;
-    run_result!(%"zfs create -o mountpoint=/ -o canmount=noauto rpool/arch/ROOT/default")?;
-    run_result!(%"zfs mount rpool/arch/ROOT/default")?;
-    run_result!(%"zfs mount bpool/arch/BOOT/default")?;
-
-    for dir in ["usr", "var", "var/lib"] {
-        eprintln!("{}", dir);
-        let dset = "rpool/arch/DATA/default/".to_owned() + dir;
-        run_result!(%"zfs create -o canmount=off", dset)?;
-    }
-
-    for dir in ["home", "root", "srv", "usr/local", "var/log", "var/spool"] {
-        eprintln!("{}", dir);
-        let dset = "rpool/arch/DATA/default/".to_owned() + dir;
-        run_result!(%"zfs create -o canmount=on", dset)?;
-    }
-    run_result!(%"chmod 750 /mnt/root")?;
-
-    log("Format and mount esp");
-    run_result!(%"mkfs.vfat -n EFI", &efi_part)?;
-
-    let efis_mnt = format!("/mnt/boot/efis/{}", sail.get_efi_last_path()?);
-
-    run_result!(%"mkdir -p", &efis_mnt).context("Creating efis dir")?;
-    run_result!(%"mount -t vfat", &efi_part, efis_mnt)?;
-    run_result!(%"mkdir -p /mnt/boot/efi").context("Creating efi dir")?;
-    run_result!(%"mount -t vfat", efi_part, "/mnt/boot/efi")?;
-
-    log("Optional user data datasets");
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/var/games")?;
-    run_result!(%"chmod 775 /mnt/var/games")?;
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/var/www")?;
-    log("For GNOME");
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/var/lib/AccountsService")?;
-    run_result!(%"chmod 775 /mnt/var/lib/AccountsService")?;
-    log("For Docker");
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/var/lib/docker")?;
-    log("For NFS");
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/var/lib/nfs")?;
-    log("For LXC");
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/var/lib/lxc")?;
-    log("For LibVirt");
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/var/lib/libvirt")?;
-    log("For nix");
-    run_result!(%"zfs create -o canmount=on rpool/arch/DATA/default/nix")?;
+        sail.get_rpool().get_fs_properties(),
+        sail.get_rpool_encryption(),
+        rpool,
+        sail.get_rpool_vdev());
+    if let Some(passphrase) = sail.get_passphrase() {
+        create_rpool = create_rpool.secret_stdin(passphrase);
+    }
+    ex.run(&create_rpool)?;
+    ex.push_undo(cmd!(%"zpool destroy -f", rpool));
+
+    ex.log("Create root dataset");
+    ex.run(&cmd!(%"zfs create -o canmount=off -o mountpoint=none", format!("{}/arch", rpool)))?;
+
+    ex.log("Create other dataset");
+    ex.run(&cmd!(%"zfs create -o canmount=off -o mountpoint=none", format!("{}/arch", bpool)))?;
+    ex.run(
+        &cmd!(%"zfs create -o canmount=off -o mountpoint=none", format!("{}/arch/BOOT", bpool)),
+    )?;
+    ex.run(
+        &cmd!(%"zfs create -o canmount=off -o mountpoint=none", format!("{}/arch/ROOT", rpool)),
+    )?;
+    ex.run(
+        &cmd!(%"zfs create -o canmount=off -o mountpoint=none", format!("{}/arch/DATA", rpool)),
+    )?;
+    ex.run(&cmd!(%"zfs create -o mountpoint=/boot -o canmount=noauto", format!("{}/arch/BOOT/default", bpool)))?;
+    ex.run(&cmd!(%"zfs create -o mountpoint=/ -o canmount=off", format!("{}/arch/DATA/default", rpool)))?;
+    ex.run(&cmd!(%"zfs create -o mountpoint=/ -o canmount=noauto", format!("{}/arch/ROOT/default", rpool)))?;
+    ex.run(&cmd!(%"zfs mount", format!("{}/arch/ROOT/default", rpool)))?;
+    ex.run(&cmd!(%"zfs mount", format!("{}/arch/BOOT/default", bpool)))?;
+    // /mnt/boot sits in rpool's /mnt, destroying rpool first would fail busy
+    ex.push_undo(cmd!(%"zfs unmount", format!("{}/arch/BOOT/default", bpool)));
+
+    ex.log("Create data datasets");
+    for dataset in sail.get_datasets() {
+        let dset = format!("{}/arch/DATA/default/{}", rpool, dataset.name);
+        ex.run(&cmd!(%"zfs create", dataset.get_options(), dset))?;
+        if let Some(mode) = &dataset.chmod {
+            let path = format!("/mnt{}", dataset.get_mountpoint());
+            ex.run(&cmd!("chmod", mode, path))?;
+        }
+    }
+
+    ex.log("Format and mount esp");
+    for disk in sail.get_disks() {
+        let efi_part = disk.get_efi_part();
+        ex.run(&cmd!(%"mkfs.vfat -n EFI", &efi_part))?;
+
+        let efis_mnt = format!("/mnt/boot/efis/{}", disk.get_efi_last_path()?);
+
+        ex.run(&cmd!(%"mkdir -p", &efis_mnt))
+            .context("Creating efis dir")?;
+        ex.run(&cmd!(%"mount -t vfat", efi_part, &efis_mnt))?;
+        ex.push_undo(cmd!("umount", efis_mnt));
+    }
+    ex.run(&cmd!(%"mkdir -p /mnt/boot/efi"))
+        .context("Creating efi dir")?;
+    let efi_part = sail.get_boot_disk().get_efi_part();
+    ex.run(&cmd!(%"mount -t vfat", efi_part, "/mnt/boot/efi"))?;
+    ex.push_undo(cmd!(%"umount /mnt/boot/efi"));
 
     Ok(())
 }
 
-pub fn pacstrap(sail: &Sail) -> Result<()> {
-    let base = [
-        "base",
-        "base-devel",
-        "dosfstools",
-        "grub",
-        "git",
-        "mandoc",
-        "mkinitcpio",
-        "nano",
-        "neovim",
-        "networkmanager",
-        "os-prober",
-        "reflector",
-        "sudo",
-        "zsh",
-    ];
-    let linux = sail.get_linvar();
-    let linux_headers = linux.to_owned() + "-headers";
-    let zfs = sail.get_zfs();
+/// Import the pools and mount everything under /mnt again, to resume an
+/// interrupted installation
+pub fn remount(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    let bpool = sail.get_bpool().get_name();
+    let rpool = sail.get_rpool().get_name();
 
-    log("Update pacman repository");
-    run_result!(%"pacman -Sy")?;
+    ex.log("Load zfs kernel module");
+    ex.run(&cmd!(%"modprobe zfs"))?;
 
-    log("Check compatible kernel version");
-    let StdoutTrimmed(out) = run_result!(%"pacman -Si", zfs)?;
-    let StdoutTrimmed(out) = run_result!("grep", "Depends On", Stdin(out))?;
-    let exp = format!("s|.*{}=||", linux);
-    let StdoutTrimmed(out) = run_result!("sed", exp, Stdin(out))?;
-    let StdoutTrimmed(req_linver) = run_result!("awk", "{ print $1 }", Stdin(out))?;
+    ex.log("Import pools");
+    for pool in [rpool, bpool] {
+        if ex.output(&cmd!(%"zpool list -H -o name", pool)).is_err() {
+            ex.run(&cmd!(%"zpool import -N -R /mnt", pool))?;
+        }
+    }
 
-    log("Check repo kernel version");
-    let StdoutTrimmed(out) = run_result!(%"pacman -Si", linux)?;
-    let StdoutTrimmed(out) = run_result!(%"grep Version", Stdin(out))?;
-    let StdoutTrimmed(repo_linver) = run_result!("awk", "{ print $3 }", Stdin(out))?;
+    if sail.is_encrypted() {
+        ex.log("Load key of root pool");
+        let keystatus = ex.output(&cmd!(%"zfs get -H -o value keystatus", rpool))?;
+        if keystatus != "available" {
+            ex.run(&load_key(sail))?;
+        }
+    }
 
-    log("Install base packages");
-    run_result!(%"pacstrap -c /mnt", base)?;
+    ex.log("Mount datasets");
+    if ex.output(&cmd!(%"mountpoint -q /mnt")).is_err() {
+        ex.run(&cmd!(%"zfs mount", format!("{}/arch/ROOT/default", rpool)))?;
+    }
+    if ex.output(&cmd!(%"mountpoint -q /mnt/boot")).is_err() {
+        ex.run(&cmd!(%"zfs mount", format!("{}/arch/BOOT/default", bpool)))?;
+        // /mnt/boot sits in rpool's /mnt, destroying rpool first would fail busy
+        ex.push_undo(cmd!(%"zfs unmount", format!("{}/arch/BOOT/default", bpool)));
+    }
+    ex.run(&cmd!(%"zfs mount -a"))?;
+
+    ex.log("Mount esp");
+    for disk in sail.get_disks() {
+        let efis_mnt = format!("/mnt/boot/efis/{}", disk.get_efi_last_path()?);
+        if ex.output(&cmd!(%"mountpoint -q", &efis_mnt)).is_err() {
+            ex.run(&cmd!(%"mount -t vfat", disk.get_efi_part(), efis_mnt))?;
+        }
+    }
+    if ex.output(&cmd!(%"mountpoint -q /mnt/boot/efi")).is_err() {
+        let efi_part = sail.get_boot_disk().get_efi_part();
+        ex.run(&cmd!(%"mount -t vfat", efi_part, "/mnt/boot/efi"))?;
+    }
 
-    log("Install kernel, download from archive if not available");
-    if req_linver == repo_linver {
-        log("Install from repo");
-        run_result!(%"pacstrap -c /mnt", linux, linux_headers)?;
-    } else {
-        let url = format!(
-            "https://archive.archlinux.org/packages/l/{linux}/{linux}-{linver}-x86_64.pkg.tar.zst",
-            linux = linux,
-            linver = req_linver
-        );
-        eprintln!("Install manually from {}\n", url);
-        run_result!(%"pacstrap -U /mnt", url)?;
-        run_result!(%"pacstrap -c /mnt", linux_headers)?;
+    Ok(())
+}
+
+/// Load the key of rpool from where it is on the live system, the
+/// keylocation of rpool may already point inside the new system
+fn load_key(sail: &Sail) -> Cmd {
+    let rpool = sail.get_rpool().get_name();
+
+    match (sail.get_passphrase(), sail.get_keyfile()) {
+        (Some(passphrase), _) => cmd!(%"zfs load-key -L prompt", rpool).secret_stdin(passphrase),
+        (None, Some(keyfile)) => {
+            cmd!(%"zfs load-key -L", format!("file://{}", keyfile), rpool)
+        }
+        (None, None) => cmd!(%"zfs load-key", rpool),
     }
+}
+
+/// Fill the pool names in a script of string_res
+fn with_pools(script: &str, sail: &Sail) -> String {
+    script
+        .replace("{bpool}", sail.get_bpool().get_name())
+        .replace("{rpool}", sail.get_rpool().get_name())
+}
 
-    log("Install firmware");
-    run_result!(%"pacstrap -c /mnt linux-firmware intel-ucode amd-ucode")?;
+/// pacman.conf of the live system when installing from the local repository
+const LOCAL_PACMAN_CONF: &str = "/tmp/sail-pacman.conf";
 
-    log("Install zfs");
-    run_result!(%"pacstrap -c /mnt", zfs, "zfs-utils")?;
+/// `flag` with the pacman.conf of the local repository when installing
+/// offline, pacman takes it as --config and pacstrap as -C
+fn pacman_conf(sail: &Sail, flag: &str) -> Vec<String> {
+    match sail.get_repo() {
+        Some(_) => vec![flag.to_owned(), LOCAL_PACMAN_CONF.to_owned()],
+        None => Vec::new(),
+    }
+}
+
+/// Let the keyring in `gpgdir` trust `key` saved in `repo`, `keyid` is its
+/// fingerprint
+fn trust_key(
+    repo: &LocalRepo,
+    key: &str,
+    keyid: &str,
+    gpgdir: &str,
+    ex: &mut dyn Executor,
+) -> Result<()> {
+    ex.run(&cmd!(%"pacman-key --gpgdir", gpgdir, "-a", repo.get_file(key)))?;
+    ex.run(&cmd!(%"pacman-key --gpgdir", gpgdir, "--lsign-key", keyid))?;
 
     Ok(())
 }
 
-pub fn system_configuration(sail: &Sail) -> Result<()> {
-    let arch_chroot = Split("arch-chroot /mnt bash --login");
+pub fn pacstrap(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    let linux = sail.get_linvar();
+    let linux_headers = linux.to_owned() + "-headers";
+    let zfs = sail.get_zfs();
+
+    if let Some(repo) = sail.get_repo() {
+        ex.log("Use the local repository");
+        let pacman_c = string_res::LOCAL_PACMAN_C.replace("{repo}", repo.get_path());
+        ex.writeln_w(&pacman_c, LOCAL_PACMAN_CONF)?;
+        // `sail cache build` signs every package with the key of the repository
+        let keyid = repo.get_keyid();
+        trust_key(repo, cache::REPO_KEY, keyid, "/etc/pacman.d/gnupg", ex)?;
+    }
+
+    ex.log("Update pacman repository");
+    ex.run(&cmd!(%"pacman -Sy", pacman_conf(sail, "--config")))?;
+
+    ex.log("Check kernel version compatible with zfs");
+    let (kernels, zfs_packages) = match sail.get_repo() {
+        // pacman -Si would read the pacman.conf above, which a dry run
+        // only prints
+        Some(repo) => {
+            let db = repo.get_file(cache::REPO_DB);
+            (
+                package::find_in_db(ex, &db, linux)?,
+                package::find_in_db(ex, &db, zfs)?,
+            )
+        }
+        None => (package::find(ex, linux, &[])?, package::find(ex, zfs, &[])?),
+    };
+    let kernel = package::pick_kernel(&kernels, &zfs_packages)?;
+
+    ex.log("Install base packages");
+    let packages = sail.get_packages();
+    ex.run(&cmd!("pacstrap", pacman_conf(sail, "-C"), %"-c /mnt", packages.get_base()))?;
+
+    ex.log("Install kernel, download from archive if not available");
+    let zfs_repository = kernel.get_zfs_repository().to_owned();
+    match kernel {
+        KernelSource::Repo {
+            repository,
+            version,
+            ..
+        } => {
+            ex.log(&format!("Install {} {} from {}", linux, version, repository));
+            let kernel = format!("{}/{}", repository, linux);
+            let headers = format!("{}/{}", repository, linux_headers);
+            ex.run(&cmd!("pacstrap", pacman_conf(sail, "-C"), %"-c /mnt", kernel, headers))?;
+        }
+        KernelSource::Archive { version, .. } if sail.get_repo().is_some() => bail!(
+            "{} needs {} {}, which isn't in the local repository, rebuild it with `sail cache build`",
+            zfs,
+            linux,
+            version
+        ),
+        KernelSource::Archive { version, .. } => {
+            let cache = "/mnt/var/cache/pacman/pkg";
+            let mut files = Vec::new();
+            for name in [linux, &linux_headers] {
+                files.push(package::fetch_archived(ex, name, &version, cache)?);
+            }
+            ex.run(&cmd!(%"pacstrap -U /mnt", files))?;
+        }
+    }
+
+    if !packages.get_firmware().is_empty() {
+        ex.log("Install firmware");
+        ex.run(&cmd!("pacstrap", pacman_conf(sail, "-C"), %"-c /mnt", packages.get_firmware()))?;
+    }
 
-    log("Set grub flag to use os-prober");
-    let grub_osprober_c = "GRUB_DISABLE_OS_PROBER=false\n";
-    writeln_a(grub_osprober_c, "/mnt/etc/default/grub")?;
+    ex.log(&format!("Install zfs from {}", zfs_repository));
+    let zfs = format!("{}/{}", zfs_repository, zfs);
+    let zfs_utils = format!("{}/zfs-utils", zfs_repository);
+    ex.run(&cmd!("pacstrap", pacman_conf(sail, "-C"), %"-c /mnt", zfs, zfs_utils))?;
 
-    log("Generate fstab");
-    let StdoutTrimmed(out) = run_result!(%"genfstab -U /mnt")?;
-    let StdoutTrimmed(out) = run_result!(%"sed", "s;zfs[[:space:]]*;zfs zfsutil,;g", Stdin(out))?;
-    let StdoutTrimmed(fstab_zfs) = run_result!(%"grep", "zfs zfsutil", Stdin(out))?;
-    writeln_w(&fstab_zfs, "/mnt/etc/fstab")?;
+    if !packages.get_extra().is_empty() {
+        ex.log("Install extra packages");
+        ex.run(&cmd!("pacstrap", pacman_conf(sail, "-C"), %"-c /mnt", packages.get_extra()))?;
+    }
 
-    let efi_part = sail.get_efi_part()?;
-    let StdoutTrimmed(uuid) = run_result!(%"blkid -s UUID -o value", efi_part)?;
-    let fstab_efis = format!("/boot/efis/{}", sail.get_efi_last_path()?);
-    let fstab_efis = format!("UUID={} {} {}", uuid, fstab_efis, "vfat x-systemd.idle-timeout=1min,x-systemd.automount,noauto,umask=0022,fmask=0022,dmask=0022 0 1");
-    let fstab_efi = format!("UUID={} {}", uuid, "/boot/efi vfat x-systemd.idle-timeout=1min,x-systemd.automount,noauto,umask=0022,fmask=0022,dmask=0022 0 1");
-    let fstab_efi = format!("{}\n{}", fstab_efis, fstab_efi);
-    writeln_a(&fstab_efi, "/mnt/etc/fstab")?;
+    Ok(())
+}
 
-    log("Configure mkinitcpio");
-    run_result!(%"mv /mnt/etc/mkinitcpio.conf /mnt/etc/mkinitcpio.conf.old")?;
-    let hooks_c = "HOOKS=(base udev autodetect modconf block keyboard zfs filesystems)";
-    writeln_a(hooks_c, "/mnt/etc/mkinitcpio.conf")?;
+pub fn system_configuration(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    if sail.get_packages().contains("os-prober") {
+        ex.log("Set grub flag to use os-prober");
+        let grub_osprober_c = "GRUB_DISABLE_OS_PROBER=false\n";
+        ex.writeln_a(grub_osprober_c, "/mnt/etc/default/grub")?;
+    }
 
-    log("Enable internet time sync");
-    run_result!(%"hwclock --systohc")?;
-    run_result!(%"systemctl enable systemd-timesyncd --root=/mnt")?;
+    ex.log("Generate fstab");
+    let out = ex.output(&cmd!(%"genfstab -U /mnt"))?;
+    let out = ex.output(&cmd!(%"sed", "s;zfs[[:space:]]*;zfs zfsutil,;g").stdin(out))?;
+    let fstab_zfs = ex.output(&cmd!(%"grep", "zfs zfsutil").stdin(out))?;
+    ex.writeln_w(&fstab_zfs, "/mnt/etc/fstab")?;
+
+    let mut fstab_efi = Vec::new();
+    for disk in sail.get_disks() {
+        let uuid = ex.output(&cmd!(%"blkid -s UUID -o value", disk.get_efi_part()))?;
+        let fstab_efis = format!("/boot/efis/{}", disk.get_efi_last_path()?);
+        fstab_efi.push(format!("UUID={} {} {}", uuid, fstab_efis, "vfat x-systemd.idle-timeout=1min,x-systemd.automount,noauto,umask=0022,fmask=0022,dmask=0022 0 1"));
+    }
+    let uuid = ex.output(&cmd!(%"blkid -s UUID -o value", sail.get_boot_disk().get_efi_part()))?;
+    fstab_efi.push(format!("UUID={} {}", uuid, "/boot/efi vfat x-systemd.idle-timeout=1min,x-systemd.automount,noauto,umask=0022,fmask=0022,dmask=0022 0 1"));
+    ex.writeln_a(&fstab_efi.join("\n"), "/mnt/etc/fstab")?;
+
+    ex.log("Configure mkinitcpio");
+    ex.run(&cmd!(%"mv /mnt/etc/mkinitcpio.conf /mnt/etc/mkinitcpio.conf.old"))?;
+    let hooks_c = if sail.is_encrypted() {
+        // zfs hook prompts for the passphrase, typed with the configured keymap
+        "HOOKS=(base udev autodetect modconf block keyboard keymap zfs filesystems)"
+    } else {
+        "HOOKS=(base udev autodetect modconf block keyboard zfs filesystems)"
+    };
+    ex.writeln_a(hooks_c, "/mnt/etc/mkinitcpio.conf")?;
+
+    // Only with embed_keyfile, the initramfs is readable on bpool
+    if let Some(keyfile) = sail.get_keyfile() {
+        ex.log("Install keyfile of root pool");
+        let rpool = sail.get_rpool().get_name();
+        let key = format!("/etc/zfs/{}.key", rpool);
+        ex.run(&cmd!(%"install -m 000", keyfile, format!("/mnt{}", key)))?;
+        ex.run(&cmd!(
+            "zfs",
+            "set",
+            format!("keylocation=file://{}", key),
+            rpool
+        ))?;
+        ex.writeln_a(&format!("FILES=({})", key), "/mnt/etc/mkinitcpio.conf")?;
+    }
 
-    log("Set locale, timezone, keymap");
-    run_result!(%"rm -f /mnt/etc/localtime")?;
-    run_result!(%"systemd-firstboot --root=/mnt --force --locale=en_US.UTF-8 --locale-messages=en_US.UTF-8 --keymap=us --timezone=Asia/Jakarta --hostname=lbox --root-password=123 --root-shell=/bin/zsh")?;
+    ex.log("Enable internet time sync");
+    ex.run(&cmd!(%"hwclock --systohc"))?;
+    ex.run(&cmd!(%"systemctl enable systemd-timesyncd --root=/mnt"))?;
+
+    ex.log("Set locale, timezone, keymap");
+    ex.run(&cmd!(%"rm -f /mnt/etc/localtime"))?;
+    ex.run(&cmd!(%"systemd-firstboot --root=/mnt --force",
+        format!("--locale={}", sail.get_locale()),
+        format!("--locale-messages={}", sail.get_locale()),
+        format!("--keymap={}", sail.get_keymap()),
+        format!("--timezone={}", sail.get_timezone()),
+        format!("--hostname={}", sail.get_hostname()),
+        format!("--root-shell={}", sail.get_root_shell())))?;
+
+    // Through stdin, an argv would put the hash in the install log
+    if let Some(hash) = sail.get_root_password_hash() {
+        ex.log("Set root password");
+        ex.run(&cmd!(%"arch-chroot /mnt chpasswd -e").secret_stdin(format!("root:{}", hash)))?;
+    }
 
-    log("Change root password using chroot");
-    run_result!(%"arch-chroot /mnt passwd", Stdin("123\n123"))?;
+    if sail.is_root_locked() {
+        ex.log("Lock root account");
+        ex.run(&cmd!(%"arch-chroot /mnt passwd -l root"))?;
+    }
 
-    log("Generate hostid");
-    run_result!(%"zgenhostid -f -o /mnt/etc/hostid")?;
+    ex.log("Generate hostid");
+    ex.run(&cmd!(%"zgenhostid -f -o /mnt/etc/hostid"))?;
 
-    log("Ignore kernel update");
-    run_result!(%"sed -i", "s/#IgnorePkg/IgnorePkg/", "/mnt/etc/pacman.conf")?;
+    ex.log("Ignore kernel update");
+    ex.run(&cmd!(%"sed -i", "s/#IgnorePkg/IgnorePkg/", "/mnt/etc/pacman.conf"))?;
     let exp = format!(
         "/^IgnorePkg/ s/$/ {linux} {linux}-headers zfs-{linux} zfs-utils/",
         linux = sail.get_linvar()
     );
-    run_result!(%"sed -i", exp, "/mnt/etc/pacman.conf")?;
+    ex.run(&cmd!(%"sed -i", exp, "/mnt/etc/pacman.conf"))?;
 
-    log("Generate kernel_updater script in /usr/local/bin");
-    writeln_w(
+    ex.log("Generate kernel_updater script in /usr/local/bin");
+    ex.writeln_w(
         string_res::KERNEL_UPDATER_S,
         "/mnt/usr/local/bin/kernel_updater",
     )?;
-    run_result!(%"chmod +x /mnt/usr/local/bin/kernel_updater")?;
-
-    log("Enable zfs services");
-    run_result!(%"systemctl enable zfs-import-cache.service zfs-import.target zfs-zed zfs.target --root=/mnt")?;
-    run_result!(%"systemctl disable zfs-mount --root=/mnt")?;
-
-    log("Apply locales");
-    writeln_w("en_US.UTF-8 UTF-8", "/mnt/etc/locale.gen")?;
-    run_result!(&arch_chroot, Stdin("locale-gen"))?;
-
-    log("Import keys of archzfs");
-    let import_archzfs_keys_i = string_res::IMPORT_ARCHZFS_KEYS_I;
-    run_result!(&arch_chroot, Stdin(import_archzfs_keys_i))?;
+    ex.run(&cmd!(%"chmod +x /mnt/usr/local/bin/kernel_updater"))?;
+
+    ex.log("Enable zfs services");
+    ex.run(&cmd!(%"systemctl enable zfs-import-cache.service zfs-import.target zfs-zed zfs.target --root=/mnt"))?;
+    ex.run(&cmd!(%"systemctl disable zfs-mount --root=/mnt"))?;
+
+    ex.log("Apply locales");
+    ex.writeln_w(&sail.get_locale_gen().join("\n"), "/mnt/etc/locale.gen")?;
+    ex.run(&arch_chroot("locale-gen"))?;
+
+    ex.log("Import keys of archzfs");
+    match sail.get_repo() {
+        Some(repo) => {
+            let gpgdir = "/mnt/etc/pacman.d/gnupg";
+            trust_key(
+                repo,
+                cache::ARCHZFS_KEY,
+                repo.get_archzfs_keyid(),
+                gpgdir,
+                ex,
+            )?;
+            // pacstrap copied the live keyring, the repository key has no use
+            // on the new system
+            ex.run(&cmd!(%"pacman-key --gpgdir", gpgdir, "--delete", repo.get_keyid()))?;
+            let mirrorlist = repo.get_file(cache::ARCHZFS_MIRRORLIST);
+            ex.run(&cmd!(%"install -m 644", mirrorlist, "/mnt/etc/pacman.d/mirrorlist-archzfs"))?;
+        }
+        None => {
+            let import_archzfs_keys_i = string_res::IMPORT_ARCHZFS_KEYS_I;
+            ex.run(&arch_chroot(import_archzfs_keys_i))?;
+        }
+    }
 
-    log("Add archzfs repo");
-    writeln_a(string_res::ARCHZFS_REPO_C, "/mnt/etc/pacman.conf")?;
+    ex.log("Add archzfs repo");
+    ex.writeln_a(string_res::ARCHZFS_REPO_C, "/mnt/etc/pacman.conf")?;
 
     Ok(())
 }
 
-pub fn install_aurs() -> Result<()> {
-    let arch_chroot = Split("arch-chroot /mnt bash --login");
+pub fn install_aurs(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    if sail.get_repo().is_some() {
+        ex.log("Install paru, boot environment manager, its pacman hook and zrepl");
+        ex.run(&cmd!("pacstrap", pacman_conf(sail, "-C"), %"-c /mnt", cache::AUR_PACKAGES))?;
+    } else {
+        ex.log("Install paru");
+        let paru_install_i = string_res::PARU_INSTALL_I;
+        ex.run(&arch_chroot(paru_install_i))?;
 
-    log("Install paru");
-    let paru_install_i = string_res::PARU_INSTALL_I;
-    run_result!(&arch_chroot, Stdin(paru_install_i))?;
+        ex.log("Install boot environment manager");
+        let bieaz_install_i = string_res::BIEAZ_INSTALL_I;
+        ex.run(&arch_chroot(bieaz_install_i))?;
 
-    log("Install boot environment manager");
-    let bieaz_install_i = string_res::BIEAZ_INSTALL_I;
-    run_result!(&arch_chroot, Stdin(bieaz_install_i))?;
+        ex.log("Install pacman hook for BEM");
+        let bieaz_pachook_install_i = string_res::BIEAZ_PACHOOK_INSTALL_I;
+        ex.run(&arch_chroot(bieaz_pachook_install_i))?;
 
-    log("Install pacman hook for BEM");
-    let bieaz_pachook_install_i = string_res::BIEAZ_PACHOOK_INSTALL_I;
-    run_result!(&arch_chroot, Stdin(bieaz_pachook_install_i))?;
-    log("Add env_keep for rozb3 skip");
-    let env_keep_c = r#"Defaults env_keep += "ROZB3_PAC_SKIP""#;
-    writeln_a(env_keep_c, "/mnt/etc/sudoers")?;
+        ex.log("Install zrepl auto snapshotter");
+        let zrepl_install_i = string_res::ZREPL_INSTALL_I;
+        ex.run(&arch_chroot(zrepl_install_i))?;
 
-    log("Install zrepl auto snapshotter");
-    let zrepl_install_i = string_res::ZREPL_INSTALL_I;
-    run_result!(&arch_chroot, Stdin(zrepl_install_i))?;
+        ex.log("Delete temporary user");
+        ex.run(&cmd!(%"rm /mnt/etc/sudoers.d/00_nobody"))?;
+    }
 
-    log("Generate zrepl configuration");
-    run_result!(%"mkdir -p /mnt/etc/zrepl")?;
-    writeln_w(string_res::ZREPL_YML_C, "/mnt/etc/zrepl/zrepl.yml")?;
+    ex.log("Add env_keep for rozb3 skip");
+    let env_keep_c = r#"Defaults env_keep += "ROZB3_PAC_SKIP""#;
+    ex.writeln_a(env_keep_c, "/mnt/etc/sudoers")?;
 
-    log("Delete temporary user");
-    run_result!(%"rm /mnt/etc/sudoers.d/00_nobody")?;
+    ex.log("Generate zrepl configuration");
+    ex.run(&cmd!(%"mkdir -p /mnt/etc/zrepl"))?;
+    let zrepl_yml_c = with_pools(string_res::ZREPL_YML_C, sail);
+    ex.writeln_w(&zrepl_yml_c, "/mnt/etc/zrepl/zrepl.yml")?;
 
     Ok(())
 }
 
-pub fn workarounds() -> Result<()> {
-    log("Grub canonical path fix");
+pub fn workarounds(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    ex.log("Grub canonical path fix");
     let canonical_fix_c = "export ZPOOL_VDEV_NAME_PATH=YES";
     let env_keep_c = r#"Defaults env_keep += "ZPOOL_VDEV_NAME_PATH""#;
-    writeln_w(
+    ex.writeln_w(
         canonical_fix_c,
         "/mnt/etc/profile.d/zpool_vdev_name_path.sh",
     )?;
-    writeln_a(env_keep_c, "/mnt/etc/sudoers")?;
+    ex.writeln_a(env_keep_c, "/mnt/etc/sudoers")?;
 
-    log("Pool name missing fix");
-    let exp = r"s|rpool=.*|rpool=rpool|";
-    run_result!(%"sed -i", exp, "/mnt/etc/grub.d/10_linux")?;
+    ex.log("Pool name missing fix");
+    let exp = format!("s|rpool=.*|rpool={}|", sail.get_rpool().get_name());
+    ex.run(&cmd!(%"sed -i", exp, "/mnt/etc/grub.d/10_linux"))?;
 
-    log("Add zfs_import_dir to GRUB");
+    ex.log("Add zfs_import_dir to GRUB");
     let zfs_import_dir_c = r#"GRUB_CMDLINE_LINUX="zfs_import_dir=/dev/disk/by-id/""#;
-    writeln_a(zfs_import_dir_c, "/mnt/etc/default/grub")?;
+    ex.writeln_a(zfs_import_dir_c, "/mnt/etc/default/grub")?;
 
     Ok(())
 }
 
-pub fn bootloaders(sail: &Sail) -> Result<()> {
-    let arch_chroot = Split("arch-chroot /mnt bash --login");
-
-    log("Generate initrd");
-    let gen_initrd_i = string_res::GEN_INITRD_I;
-    run_result!(&arch_chroot, Stdin(gen_initrd_i))?;
+pub fn bootloaders(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    ex.log("Generate initrd");
+    let gen_initrd_i = with_pools(string_res::GEN_INITRD_I, sail);
+    ex.run(&arch_chroot(&gen_initrd_i))?;
 
-    log("Set ZPOOL_VDEV_NAME_PATH workaround");
+    ex.log("Set ZPOOL_VDEV_NAME_PATH workaround");
     env::set_var("ZPOOL_VDEV_NAME_PATH", "YES");
 
-    log("Create grub boot dir, in esp and boot pool");
-    run_result!(%"mkdir -p /mnt/boot/efi/arch/grub-bootdir/i386-pc/")?;
-    run_result!(%"mkdir -p /mnt/boot/efi/arch/grub-bootdir/x86_64-efi/")?;
+    ex.log("Create grub boot dir, in esp and boot pool");
+    ex.run(&cmd!(%"mkdir -p /mnt/boot/efi/arch/grub-bootdir/i386-pc/"))?;
+    ex.run(&cmd!(%"mkdir -p /mnt/boot/efi/arch/grub-bootdir/x86_64-efi/"))?;
 
-    log("Install grub efi");
+    ex.log("Install grub efi");
     let grub_install_2i = string_res::GRUB_INSTALL_2I;
-    let grub_install_1i = format!(
-        "grub-install --target=i386-pc --boot-directory /boot/efi/arch/grub-bootdir/i386-pc/ {}",
-        sail.get_disk()
-    );
-    let grub_setup_i = [&grub_install_1i, grub_install_2i, "\n"].concat();
-    run_result!(&arch_chroot, Stdin(grub_setup_i))?;
-
-    log("Generate grub menu");
+    let grub_install_1i: Vec<String> = sail
+        .get_disks()
+        .iter()
+        .map(|disk| {
+            format!(
+                "grub-install --target=i386-pc --boot-directory /boot/efi/arch/grub-bootdir/i386-pc/ {}",
+                disk.get_path()
+            )
+        })
+        .collect();
+    let grub_setup_i = [&grub_install_1i.join("\n"), grub_install_2i, "\n"].concat();
+    ex.run(&arch_chroot(&grub_setup_i))?;
+
+    ex.log("Generate grub menu");
     let grub_menu_i = string_res::GRUB_MENU_I;
-    run_result!(&arch_chroot, Stdin(grub_menu_i))?;
+    ex.run(&arch_chroot(grub_menu_i))?;
 
-    log("Mirror esp content");
+    ex.log("Mirror esp content");
     let mirror_esp_i = string_res::MIRROR_ESP_I;
-    run_result!(&arch_chroot, Stdin(mirror_esp_i))?;
+    ex.run(&arch_chroot(mirror_esp_i))?;
 
     Ok(())
 }
 
-pub fn finishing(sail: &Sail) -> Result<()> {
-    log("Generate monthly scrub service");
-    writeln_w(
+pub fn finishing(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    ex.log("Generate monthly scrub service");
+    ex.writeln_w(
         string_res::SCRUB_TIMER_C,
         "/mnt/etc/systemd/system/zfs-scrub@.timer",
     )?;
-    writeln_w(
+    ex.writeln_w(
         string_res::SCRUB_SERVICE_C,
         "/mnt/etc/systemd/system/zfs-scrub@.service",
     )?;
 
     if sail.is_using_ssd() {
-        log("Generate monthly trim service");
-        writeln_w(
+        ex.log("Generate monthly trim service");
+        ex.writeln_w(
             string_res::TRIM_TIMER_C,
             "/mnt/etc/systemd/system/zfs-trim@.timer",
         )?;
-        writeln_w(
+        ex.writeln_w(
             string_res::TRIM_SERVICE_C,
             "/mnt/etc/systemd/system/zfs-trim@.service",
         )?;
     }
 
-    log("Enable systemd services");
-    let arch_chroot = Split("arch-chroot /mnt bash --login");
-    let service_enable_i = string_res::SERVICE_ENABLE_I;
-    run_result!(&arch_chroot, Stdin(service_enable_i))?;
+    ex.log("Enable systemd services");
+    let service_enable_i = with_pools(string_res::SERVICE_ENABLE_I, sail);
+    ex.run(&arch_chroot(&service_enable_i))?;
     if sail.is_using_ssd() {
-        let trim_enable_i = string_res::TRIM_ENABLE_I;
-        run_result!(&arch_chroot, Stdin(trim_enable_i))?;
+        let trim_enable_i = with_pools(string_res::TRIM_ENABLE_I, sail);
+        ex.run(&arch_chroot(&trim_enable_i))?;
+    }
+
+    ex.log("Add wheel to sudoers");
+    ex.writeln_a("%wheel ALL=(ALL) ALL", "/mnt/etc/sudoers")?;
+
+    for user in sail.get_users() {
+        create_user(sail, user, ex)?;
+    }
+
+    Ok(())
+}
+
+fn create_user(sail: &Sail, user: &User, ex: &mut dyn Executor) -> Result<()> {
+    let name = &user.name;
+    let home = format!("/home/{}", name);
+    let owner = format!("{}:{}", name, name);
+
+    ex.log(&format!("Create home dataset of {}", name));
+    let dset = format!(
+        "{}/arch/DATA/default/home/{}",
+        sail.get_rpool().get_name(),
+        name
+    );
+    ex.run(&cmd!(%"zfs create -o canmount=on", &dset))?;
+    // zfs-mount is disabled, datasets are mounted through fstab
+    let fstab_home = format!("{} {} zfs zfsutil,rw,xattr,posixacl 0 0", dset, home);
+    ex.writeln_a(&fstab_home, "/mnt/etc/fstab")?;
+
+    ex.log(&format!("Create user {}", name));
+    let groups = user.get_groups();
+    // Groups of packages not installed here, e.g. docker, would fail useradd
+    for group in &groups {
+        ex.run(&cmd!(%"arch-chroot /mnt groupadd -f", group))?;
+    }
+    let groups = if groups.is_empty() {
+        Vec::new()
+    } else {
+        vec!["-G".to_owned(), groups.join(",")]
+    };
+    ex.run(&cmd!(%"arch-chroot /mnt useradd -M -U -s", &user.shell, groups, name))?;
+    ex.run(&cmd!(%"arch-chroot /mnt cp -a /etc/skel/.", &home))?;
+    ex.run(&cmd!(%"arch-chroot /mnt chown -R", &owner, &home))?;
+    ex.run(&cmd!(%"arch-chroot /mnt chmod 700", &home))?;
+
+    if let Some(hash) = &user.password_hash {
+        ex.log(&format!("Set password of {}", name));
+        let name_hash = format!("{}:{}", name, hash);
+        ex.run(&cmd!(%"arch-chroot /mnt chpasswd -e").secret_stdin(name_hash))?;
     }
 
-    log("Add wheel to sudoers");
-    writeln_a("%wheel ALL=(ALL) ALL", "/mnt/etc/sudoers")?;
+    if !user.ssh_authorized_keys.is_empty() {
+        ex.log(&format!("Add ssh authorized keys of {}", name));
+        let ssh_dir = format!("{}/.ssh", home);
+        ex.run(&cmd!(%"mkdir -p -m 700", format!("/mnt{}", ssh_dir)))?;
+        ex.writeln_w(
+            &user.ssh_authorized_keys.join("\n"),
+            &format!("/mnt{}/authorized_keys", ssh_dir),
+        )?;
+        ex.run(&cmd!(%"chmod 600", format!("/mnt{}/authorized_keys", ssh_dir)))?;
+        ex.run(&cmd!(%"arch-chroot /mnt chown -R", &owner, ssh_dir))?;
+    }
 
     Ok(())
 }
 
-pub fn post_scripts_gen() -> Result<()> {
-    log("Generating post-installation scripts");
+pub fn post_scripts_gen(ex: &mut dyn Executor) -> Result<()> {
+    ex.log("Generating post-installation scripts");
     let post_scripts_p = "/mnt/root/post_install_scripts";
-    run_result!(%"mkdir -p", post_scripts_p)?;
-
-    let path_script_pairs = [
-        ("additional_storage.sh", string_res::ADDITIONAL_STORAGE_S),
-        ("add_user.sh", string_res::ADD_USER_S),
-        ("enable_services.sh", string_res::ENABLE_SERVICES_S),
-        ("nix_install.sh", string_res::NIX_INSTALL_S),
-        ("gnome_install.sh", string_res::GNOME_INSTALL_S),
-        ("zfs_mount_generator.sh", string_res::ZFS_MOUNT_GENERATOR_S),
-    ];
+    ex.run(&cmd!(%"mkdir -p", post_scripts_p))?;
 
-    for pair in path_script_pairs {
-        let path = [post_scripts_p, "/", pair.0].concat();
-        writeln_w(pair.1, &path)?;
+    for post_script in &post_scripts::POST_SCRIPTS {
+        let path = [post_scripts_p, "/", post_script.name].concat();
+        ex.writeln_w(post_script.script, &path)?;
     }
 
     Ok(())
 }
 
-pub fn shot_and_clean() -> Result<()> {
-    log("Snapshot of clean installation");
-    run_result!(%"zfs snapshot -r rpool/arch@install")?;
-    run_result!(%"zfs snapshot -r bpool/arch@install")?;
+pub fn shot_and_clean(sail: &Sail, ex: &mut dyn Executor) -> Result<()> {
+    let bpool = sail.get_bpool().get_name();
+    let rpool = sail.get_rpool().get_name();
 
-    log("Unmount efi partition");
-    run_result!(%"umount /mnt/boot/efi")?;
-    run_result!(%"bash --login", Stdin("umount /mnt/boot/efis/*\n"))?;
+    ex.log("Save the install log and config");
+    let name = format!(
+        "/mnt/var/log/sail/install-{}",
+        Local::now().format("%Y%m%d-%H%M%S")
+    );
+    ex.run(&cmd!(%"install -d -m 700 /mnt/var/log/sail"))?;
+    let conf = format!(
+        "# Effective configuration of this installation, secrets are redacted\n{}",
+        sail.get_effective_conf().trim_end()
+    );
+    ex.writeln_w(&conf, &format!("{}.toml", name))?;
+    ex.run(&cmd!(%"chmod 600", format!("{}.toml", name)))?;
+    ex.run(&cmd!(%"install -m 600", INSTALL_LOG_PATH, format!("{}.log", name)))?;
 
-    log("Export pools");
-    run_result!(%"zpool export bpool")?;
-    run_result!(%"zpool export rpool")?;
+    ex.log("Snapshot of clean installation");
+    ex.run(&cmd!(%"zfs snapshot -r", format!("{}/arch@install", rpool)))?;
+    ex.run(&cmd!(%"zfs snapshot -r", format!("{}/arch@install", bpool)))?;
+
+    ex.log("Unmount efi partition");
+    ex.run(&cmd!(%"umount /mnt/boot/efi"))?;
+    ex.run(&cmd!(%"bash --login").stdin("umount /mnt/boot/efis/*\n"))?;
+
+    ex.log("Export pools");
+    ex.run(&cmd!(%"zpool export", bpool))?;
+    ex.run(&cmd!(%"zpool export", rpool))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{Event, Recorder};
+    use crate::sail::{
+        CanMount, Dataset, Encryption, EncryptionKey, Packages, Profile, Topology, User,
+    };
+
+    const DISK: &str = "/dev/disk/by-id/ata-DISK";
+    const DISK2: &str = "/dev/disk/by-id/ata-DISK2";
+
+    #[test]
+    fn partition_disk_appends_after_last_partition() {
+        let sail = Sail::for_test(DISK, 3);
+        let mut rec = Recorder::default();
+        partition_disk(&sail, &mut rec).unwrap();
+
+        assert_eq!(
+            rec.argvs_of(&["sgdisk"]),
+            [
+                vec!["sgdisk", "-n3:0:+512M", "-t3:EF00", DISK],
+                vec!["sgdisk", "-n4:0:+4G", "-t4:BE00", DISK],
+                vec!["sgdisk", "-n5:0:0", "-t5:BF00", DISK],
+            ]
+        );
+    }
+
+    #[test]
+    fn partition_disk_registers_partition_deletion() {
+        let sail = Sail::for_test(DISK, 3);
+        let mut rec = Recorder::default();
+        partition_disk(&sail, &mut rec).unwrap();
+
+        let undo: Vec<_> = rec
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Undo(argv) => Some(argv.join(" ")),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            undo,
+            [
+                "partprobe".to_owned(),
+                format!("sgdisk -d3 {}", DISK),
+                format!("sgdisk -d4 {}", DISK),
+                format!("sgdisk -d5 {}", DISK),
+            ]
+        );
+    }
+
+    #[test]
+    fn partition_disk_wipes_before_partitioning() {
+        let sail = Sail::for_test(DISK, 4).with_wipe();
+        let mut rec = Recorder::default().with_output(&["lsblk"], "/dev/sda\n/dev/sda1");
+        partition_disk(&sail, &mut rec).unwrap();
+
+        let argvs: Vec<_> = rec.argvs().iter().map(|argv| argv.join(" ")).collect();
+        assert_eq!(
+            argvs[..8],
+            [
+                format!("lsblk -lnpo NAME {}", DISK),
+                "zpool labelclear -f /dev/sda1".to_owned(),
+                "wipefs -a /dev/sda1".to_owned(),
+                "zpool labelclear -f /dev/sda".to_owned(),
+                "wipefs -a /dev/sda".to_owned(),
+                format!("sgdisk --zap-all {}", DISK),
+                format!("blkdiscard -f {}", DISK),
+                "partprobe".to_owned(),
+            ]
+        );
+        assert_eq!(argvs[8], format!("sgdisk -n1:0:+512M -t1:EF00 {}", DISK));
+    }
+
+    #[test]
+    fn partition_disk_partitions_every_disk() {
+        let sail = Sail::for_test(DISK, 1).with_disks(&[DISK, DISK2], Topology::Mirror);
+        let mut rec = Recorder::default();
+        partition_disk(&sail, &mut rec).unwrap();
+
+        assert_eq!(
+            rec.argvs_of(&["sgdisk"]),
+            [
+                vec!["sgdisk", "-n1:0:+512M", "-t1:EF00", DISK],
+                vec!["sgdisk", "-n2:0:+4G", "-t2:BE00", DISK],
+                vec!["sgdisk", "-n3:0:0", "-t3:BF00", DISK],
+                vec!["sgdisk", "-n1:0:+512M", "-t1:EF00", DISK2],
+                vec!["sgdisk", "-n2:0:+4G", "-t2:BE00", DISK2],
+                vec!["sgdisk", "-n3:0:0", "-t3:BF00", DISK2],
+            ]
+        );
+    }
+
+    #[test]
+    fn format_disk_creates_pools_on_new_partitions() {
+        let sail = Sail::for_test(DISK, 1);
+        let mut rec = Recorder::default();
+        format_disk(&sail, &mut rec).unwrap();
+
+        let bpool_part = format!("{}-part2", DISK);
+        let rpool_part = format!("{}-part3", DISK);
+        assert_eq!(
+            rec.argvs_of(&["zpool", "create"]),
+            [
+                vec![
+                    "zpool",
+                    "create",
+                    "-f",
+                    "-o",
+                    "compatibility=grub2",
+                    "-o",
+                    "ashift=12",
+                    "-o",
+                    "autotrim=on",
+                    "-O",
+                    "acltype=posixacl",
+                    "-O",
+                    "canmount=off",
+                    "-O",
+                    "compression=lz4",
+                    "-O",
+                    "devices=off",
+                    "-O",
+                    "normalization=formD",
+                    "-O",
+                    "relatime=on",
+                    "-O",
+                    "xattr=sa",
+                    "-O",
+                    "mountpoint=/boot",
+                    "-R",
+                    "/mnt",
+                    "bpool",
+                    &bpool_part,
+                ],
+                vec![
+                    "zpool",
+                    "create",
+                    "-f",
+                    "-o",
+                    "ashift=12",
+                    "-o",
+                    "autotrim=on",
+                    "-R",
+                    "/mnt",
+                    "-O",
+                    "acltype=posixacl",
+                    "-O",
+                    "canmount=off",
+                    "-O",
+                    "compression=zstd",
+                    "-O",
+                    "dnodesize=auto",
+                    "-O",
+                    "normalization=formD",
+                    "-O",
+                    "relatime=on",
+                    "-O",
+                    "xattr=sa",
+                    "-O",
+                    "mountpoint=/",
+                    "rpool",
+                    &rpool_part,
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn format_disk_builds_vdev_of_topology() {
+        let sail = Sail::for_test(DISK, 1).with_disks(&[DISK, DISK2], Topology::Mirror);
+        let mut rec = Recorder::default();
+        format_disk(&sail, &mut rec).unwrap();
+
+        let vdevs: Vec<_> = rec
+            .argvs_of(&["zpool", "create"])
+            .into_iter()
+            .map(|argv| argv[argv.len() - 4..].join(" "))
+            .collect();
+        assert_eq!(
+            vdevs,
+            [
+                format!("bpool mirror {}-part2 {}-part2", DISK, DISK2),
+                format!("rpool mirror {}-part3 {}-part3", DISK, DISK2),
+            ]
+        );
+
+        let esp_mounts = rec.argvs_of(&["mount", "-t", "vfat"]);
+        assert_eq!(
+            esp_mounts,
+            [
+                vec![
+                    "mount",
+                    "-t",
+                    "vfat",
+                    &format!("{}-part1", DISK),
+                    "/mnt/boot/efis/ata-DISK-part1"
+                ],
+                vec![
+                    "mount",
+                    "-t",
+                    "vfat",
+                    &format!("{}-part1", DISK2),
+                    "/mnt/boot/efis/ata-DISK2-part1"
+                ],
+                vec![
+                    "mount",
+                    "-t",
+                    "vfat",
+                    &format!("{}-part1", DISK),
+                    "/mnt/boot/efi"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn format_disk_encrypts_root_pool() {
+        let sail = Sail::for_test(DISK, 1).with_encryption(Encryption {
+            key: EncryptionKey::Passphrase,
+            passphrase: "correct horse".to_owned(),
+            ..Encryption::default()
+        });
+        let mut rec = Recorder::default();
+        format_disk(&sail, &mut rec).unwrap();
+
+        let create_rpool = rec.events.iter().find_map(|event| match event {
+            Event::Run(argv, stdin) if argv.contains(&"rpool".to_owned()) => Some((argv, stdin)),
+            _ => None,
+        });
+        let (argv, stdin) = create_rpool.unwrap();
+        let argv = argv.join(" ");
+        assert!(argv.contains(
+            "-O encryption=aes-256-gcm -O keyformat=passphrase -O keylocation=prompt rpool"
+        ));
+        assert_eq!(stdin.as_deref(), Some("correct horse"));
+    }
+
+    #[test]
+    fn pool_names_reach_generated_scripts() {
+        let sail = Sail::for_test(DISK, 1).with_pool_names("bpool_a", "rpool_a");
+        let mut rec = Recorder::default();
+        format_disk(&sail, &mut rec).unwrap();
+        workarounds(&sail, &mut rec).unwrap();
+        bootloaders(&sail, &mut rec).unwrap();
+        finishing(&sail, &mut rec).unwrap();
+
+        let pools: Vec<_> = rec
+            .argvs_of(&["zpool", "create"])
+            .iter()
+            .map(|argv| argv[argv.len() - 2])
+            .collect();
+        assert_eq!(pools, ["bpool_a", "rpool_a"]);
+        assert!(rec.argvs_of(&["sed", "-i"]).contains(&vec![
+            "sed",
+            "-i",
+            "s|rpool=.*|rpool=rpool_a|",
+            "/mnt/etc/grub.d/10_linux"
+        ]));
+
+        let scripts: String = rec
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Run(_, Some(stdin)) => Some(stdin.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(scripts.contains("zpool set cachefile=/etc/zfs/zpool.cache rpool_a\n"));
+        assert!(scripts.contains("systemctl enable zfs-scrub@bpool_a.timer\n"));
+        assert!(scripts.contains("systemctl enable zfs-trim@rpool_a.timer\n"));
+        assert!(!scripts.contains("{rpool}") && !scripts.contains("{bpool}"));
+    }
+
+    #[test]
+    fn format_disk_creates_datasets() {
+        let sail = Sail::for_test(DISK, 1);
+        let mut rec = Recorder::default();
+        format_disk(&sail, &mut rec).unwrap();
+
+        let zfs_creates = rec.argvs_of(&["zfs", "create"]);
+        assert_eq!(
+            zfs_creates[..4],
+            [
+                vec![
+                    "zfs",
+                    "create",
+                    "-o",
+                    "canmount=off",
+                    "-o",
+                    "mountpoint=none",
+                    "rpool/arch"
+                ],
+                vec![
+                    "zfs",
+                    "create",
+                    "-o",
+                    "canmount=off",
+                    "-o",
+                    "mountpoint=none",
+                    "bpool/arch"
+                ],
+                vec![
+                    "zfs",
+                    "create",
+                    "-o",
+                    "canmount=off",
+                    "-o",
+                    "mountpoint=none",
+                    "bpool/arch/BOOT"
+                ],
+                vec![
+                    "zfs",
+                    "create",
+                    "-o",
+                    "canmount=off",
+                    "-o",
+                    "mountpoint=none",
+                    "rpool/arch/ROOT"
+                ],
+            ]
+        );
+        assert!(zfs_creates.contains(&vec![
+            "zfs",
+            "create",
+            "-o",
+            "mountpoint=/",
+            "-o",
+            "canmount=noauto",
+            "rpool/arch/ROOT/default"
+        ]));
+        assert!(zfs_creates.contains(&vec![
+            "zfs",
+            "create",
+            "-o",
+            "canmount=on",
+            "rpool/arch/DATA/default/home"
+        ]));
+        assert_eq!(zfs_creates.len(), 25);
+    }
+
+    #[test]
+    fn format_disk_creates_configured_datasets() {
+        let container = Dataset {
+            name: "var".to_owned(),
+            canmount: CanMount::Off,
+            ..Dataset::default()
+        };
+        let postgres = Dataset {
+            name: "var/postgres".to_owned(),
+            mountpoint: Some("/var/lib/postgres".to_owned()),
+            properties: [("recordsize".to_owned(), "16k".to_owned())].into(),
+            chmod: Some("700".to_owned()),
+            ..Dataset::default()
+        };
+        let sail = Sail::for_test(DISK, 1).with_datasets(vec![container, postgres]);
+        let mut rec = Recorder::default();
+        format_disk(&sail, &mut rec).unwrap();
+
+        let data_creates: Vec<_> = rec
+            .argvs_of(&["zfs", "create"])
+            .into_iter()
+            .filter(|argv| argv.last().unwrap().starts_with("rpool/arch/DATA/default/"))
+            .collect();
+        assert_eq!(
+            data_creates,
+            [
+                vec![
+                    "zfs",
+                    "create",
+                    "-o",
+                    "canmount=off",
+                    "rpool/arch/DATA/default/var"
+                ],
+                vec![
+                    "zfs",
+                    "create",
+                    "-o",
+                    "canmount=on",
+                    "-o",
+                    "mountpoint=/var/lib/postgres",
+                    "-o",
+                    "recordsize=16k",
+                    "rpool/arch/DATA/default/var/postgres"
+                ],
+            ]
+        );
+        assert_eq!(
+            rec.argvs_of(&["chmod"]),
+            [vec!["chmod", "700", "/mnt/var/lib/postgres"]]
+        );
+    }
+
+    #[test]
+    fn system_configuration_writes_efi_fstab_entries() {
+        let sail = Sail::for_test(DISK, 1);
+        let mut rec = Recorder::default()
+            .with_output(&["blkid"], "ABCD-1234")
+            .with_output(
+                &["grep", "zfs zfsutil"],
+                "rpool/arch/ROOT/default / zfs zfsutil,rw 0 0",
+            );
+        system_configuration(&sail, &mut rec).unwrap();
+
+        assert!(rec.events.contains(&Event::Write(
+            "/mnt/etc/fstab".to_owned(),
+            "rpool/arch/ROOT/default / zfs zfsutil,rw 0 0".to_owned()
+        )));
+        let efi_entries = rec.events.iter().find_map(|event| match event {
+            Event::Append(path, content) if path == "/mnt/etc/fstab" => Some(content),
+            _ => None,
+        });
+        let efi_entries = efi_entries.unwrap();
+        assert!(efi_entries.starts_with("UUID=ABCD-1234 /boot/efis/ata-DISK-part1 vfat"));
+        assert!(efi_entries.contains("\nUUID=ABCD-1234 /boot/efi vfat"));
+    }
+
+    #[test]
+    fn root_password_hash_stays_out_of_argv() {
+        let sail = Sail::for_test(DISK, 1);
+        let mut rec = Recorder::default();
+        system_configuration(&sail, &mut rec).unwrap();
+
+        assert!(rec
+            .argvs()
+            .iter()
+            .flatten()
+            .all(|arg| !arg.contains("$6$salt$hash")));
+        assert!(rec.events.contains(&Event::Run(
+            vec![
+                "arch-chroot".to_owned(),
+                "/mnt".to_owned(),
+                "chpasswd".to_owned(),
+                "-e".to_owned()
+            ],
+            Some("root:$6$salt$hash".to_owned())
+        )));
+    }
+
+    #[test]
+    fn shot_and_clean_saves_log_before_snapshot() {
+        let sail = Sail::for_test(DISK, 1);
+        let mut rec = Recorder::default();
+        shot_and_clean(&sail, &mut rec).unwrap();
+
+        let conf = rec.events.iter().find_map(|event| match event {
+            Event::Write(path, content) if path.starts_with("/mnt/var/log/sail/install-") => {
+                Some((path, content))
+            }
+            _ => None,
+        });
+        let (path, content) = conf.unwrap();
+        assert!(path.ends_with(".toml"));
+        assert!(content.ends_with("hostname = \"lbox\""));
+
+        let argvs = rec.argvs();
+        let copy_log = argvs
+            .iter()
+            .position(|argv| argv.starts_with(&["install", "-m", "600", INSTALL_LOG_PATH]))
+            .unwrap();
+        let snapshot = argvs
+            .iter()
+            .position(|argv| argv.starts_with(&["zfs", "snapshot"]))
+            .unwrap();
+        assert!(copy_log < snapshot);
+        assert_eq!(argvs[copy_log][4], path.replace(".toml", ".log"));
+    }
+
+    #[test]
+    fn pacstrap_pairs_kernel_with_zfs() {
+        let sail = Sail::for_test(DISK, 1);
+        let linux = "Repository : core\nName : linux\nVersion : 6.6.1.arch1-1\n";
+        let zfs = "Repository : archzfs\nName : zfs-linux\nVersion : 2.2.0_6.6.1.arch1.1-1\n\
+                   Depends On : kmod  zfs-utils=2.2.0  linux=6.6.1.arch1-1\n";
+        let mut rec = Recorder::default()
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "linux"], linux)
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "zfs-linux"], zfs);
+        pacstrap(&sail, &mut rec).unwrap();
+        assert!(rec.argvs().contains(&vec![
+            "pacstrap",
+            "-c",
+            "/mnt",
+            "core/linux",
+            "core/linux-headers"
+        ]));
+
+        let zfs = zfs.replace("linux=6.6.1.arch1-1", "linux=6.5.9.arch2-1");
+        let mut rec = Recorder::default()
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "linux"], linux)
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "zfs-linux"], &zfs);
+        pacstrap(&sail, &mut rec).unwrap();
+        let cache = "/mnt/var/cache/pacman/pkg";
+        let kernel = format!("{}/linux-6.5.9.arch2-1-x86_64.pkg.tar.zst", cache);
+        let headers = format!("{}/linux-headers-6.5.9.arch2-1-x86_64.pkg.tar.zst", cache);
+        assert_eq!(
+            rec.argvs_of(&["pacman-key"]),
+            [
+                vec![
+                    "pacman-key",
+                    "--verify",
+                    &format!("{}.sig", kernel),
+                    &kernel
+                ],
+                vec![
+                    "pacman-key",
+                    "--verify",
+                    &format!("{}.sig", headers),
+                    &headers
+                ],
+            ]
+        );
+        assert_eq!(
+            rec.argvs_of(&["pacstrap", "-U"]),
+            [vec!["pacstrap", "-U", "/mnt", &kernel, &headers]]
+        );
+        // Nothing from the repo is mixed in
+        assert!(!rec
+            .argvs()
+            .iter()
+            .any(|argv| argv.ends_with(&["linux-headers"])));
+
+        let zfs = zfs.replace("linux=6.5.9.arch2-1", "");
+        let mut rec = Recorder::default()
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "linux"], linux)
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "zfs-linux"], &zfs);
+        assert!(pacstrap(&sail, &mut rec).is_err());
+        assert!(rec.argvs_of(&["pacstrap"]).is_empty());
+    }
+
+    #[test]
+    fn pacstrap_installs_the_pair_from_its_repositories() {
+        let sail = Sail::for_test(DISK, 1);
+        let linux = "Repository : core\nName : linux\nVersion : 6.6.1.arch1-1\n";
+        // archzfs comes first, but only archzfs-testing is built for core's kernel
+        let zfs = "Repository : archzfs\nName : zfs-linux\nVersion : 2.2.0_6.5.9.arch2.1-1\n\
+                   Depends On : kmod  zfs-utils=2.2.0  linux=6.5.9.arch2-1\n\n\
+                   Repository : archzfs-testing\nName : zfs-linux\nVersion : 2.2.1_6.6.1.arch1.1-1\n\
+                   Depends On : kmod  zfs-utils=2.2.1  linux=6.6.1.arch1-1\n";
+        let mut rec = Recorder::default()
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "linux"], linux)
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "zfs-linux"], zfs);
+        pacstrap(&sail, &mut rec).unwrap();
+
+        let argvs = rec.argvs();
+        assert!(argvs.contains(&vec![
+            "pacstrap",
+            "-c",
+            "/mnt",
+            "core/linux",
+            "core/linux-headers"
+        ]));
+        assert!(argvs.contains(&vec![
+            "pacstrap",
+            "-c",
+            "/mnt",
+            "archzfs-testing/zfs-linux",
+            "archzfs-testing/zfs-utils"
+        ]));
+        assert!(rec.argvs_of(&["curl"]).is_empty());
+    }
+
+    #[test]
+    fn offline_install_uses_the_local_repository() {
+        let sail = Sail::for_test(DISK, 1).with_repo("/srv/repo");
+        let db = "%FILENAME%\nlinux.pkg.tar.zst\n\n%NAME%\nlinux\n\n%VERSION%\n6.6.1.arch1-1\n\n\
+                  %FILENAME%\nzfs-linux.pkg.tar.zst\n\n%NAME%\nzfs-linux\n\n\
+                  %VERSION%\n2.2.0_6.6.1.arch1.1-1\n\n\
+                  %DEPENDS%\nkmod\nzfs-utils=2.2.0\nlinux=6.6.1.arch1-1\n\n";
+        let mut rec =
+            Recorder::default().with_output(&["bsdtar", "-xOf", "/srv/repo/sail.db", "*/desc"], db);
+        init_check(&sail, &mut rec).unwrap();
+        assert!(rec.argvs_of(&["curl"]).is_empty());
+
+        pacstrap(&sail, &mut rec).unwrap();
+        assert!(rec.events.contains(&Event::Write(
+            LOCAL_PACMAN_CONF.to_owned(),
+            string_res::LOCAL_PACMAN_C.replace("{repo}", "/srv/repo")
+        )));
+        assert!(rec.argvs().contains(&vec![
+            "pacman-key",
+            "--gpgdir",
+            "/etc/pacman.d/gnupg",
+            "-a",
+            "/srv/repo/sail.gpg"
+        ]));
+        assert!(rec
+            .argvs()
+            .contains(&vec!["pacman", "-Sy", "--config", LOCAL_PACMAN_CONF]));
+        // Nothing else than the database is read, a dry run has no pacman.conf
+        assert!(rec
+            .argvs_of(&["env", "LC_ALL=C", "pacman", "-Si"])
+            .is_empty());
+        for argv in rec.argvs_of(&["pacstrap"]) {
+            assert_eq!(argv[1..3], ["-C", LOCAL_PACMAN_CONF]);
+        }
+
+        let mut rec = Recorder::default();
+        install_aurs(&sail, &mut rec).unwrap();
+        assert!(rec.argvs_of(&["arch-chroot"]).is_empty());
+        assert_eq!(
+            rec.argvs_of(&["pacstrap"]),
+            [vec![
+                "pacstrap",
+                "-C",
+                LOCAL_PACMAN_CONF,
+                "-c",
+                "/mnt",
+                "paru-bin",
+                "bieaz",
+                "rozb3-pac",
+                "zrepl-bin"
+            ]]
+        );
+    }
+
+    #[test]
+    fn pacstrap_installs_the_selected_packages() {
+        let sail = Sail::for_test(DISK, 1).with_packages(Packages {
+            profile: Profile::Server,
+            extra: vec!["htop".to_owned()],
+            exclude: vec!["neovim".to_owned()],
+        });
+        let linux = "Repository : core\nName : linux\nVersion : 6.6.1.arch1-1\n";
+        let zfs = "Repository : archzfs\nName : zfs-linux\nVersion : 2.2.0_6.6.1.arch1.1-1\n\
+                   Depends On : kmod  zfs-utils=2.2.0  linux=6.6.1.arch1-1\n";
+        let mut rec = Recorder::default()
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "linux"], linux)
+            .with_output(&["env", "LC_ALL=C", "pacman", "-Si", "zfs-linux"], zfs);
+        pacstrap(&sail, &mut rec).unwrap();
+
+        let pacstraps = rec.argvs_of(&["pacstrap", "-c", "/mnt"]);
+        let base = &pacstraps[0];
+        assert!(base.contains(&"reflector"));
+        assert!(!base.contains(&"neovim"));
+        assert!(!base.contains(&"os-prober"));
+        assert_eq!(
+            pacstraps[2],
+            ["pacstrap", "-c", "/mnt", "linux-firmware", "intel-ucode"]
+        );
+        assert_eq!(pacstraps[4], ["pacstrap", "-c", "/mnt", "htop"]);
+
+        let mut rec = Recorder::default();
+        system_configuration(&sail, &mut rec).unwrap();
+        assert!(!rec.events.iter().any(|event| matches!(
+            event,
+            Event::Append(path, content)
+                if path == "/mnt/etc/default/grub" && content.contains("OS_PROBER")
+        )));
+    }
+
+    #[test]
+    fn missing_groups_are_created_before_the_user() {
+        let sail = Sail::for_test(DISK, 1);
+        let user = User {
+            name: "lena".to_owned(),
+            groups: vec!["docker".to_owned()],
+            sudo: true,
+            ..User::default()
+        };
+        let mut rec = Recorder::default();
+        create_user(&sail, &user, &mut rec).unwrap();
+
+        let argvs = rec.argvs_of(&["arch-chroot", "/mnt"]);
+        assert_eq!(
+            argvs[0],
+            ["arch-chroot", "/mnt", "groupadd", "-f", "docker"]
+        );
+        assert_eq!(argvs[1], ["arch-chroot", "/mnt", "groupadd", "-f", "wheel"]);
+        assert_eq!(
+            argvs[2],
+            [
+                "arch-chroot",
+                "/mnt",
+                "useradd",
+                "-M",
+                "-U",
+                "-s",
+                "/bin/zsh",
+                "-G",
+                "docker,wheel",
+                "lena"
+            ]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{bail, Context, Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+const UNITS: [(&str, u32); 5] = [("K", 10), ("M", 20), ("G", 30), ("T", 40), ("P", 50)];
+
+/// Size of a partition, e.g. "512M", "1.5G", "2GiB", or "25%" of the free
+/// space: the largest free block of the smallest disk, where sgdisk puts
+/// the partitions, or the whole usable disk when wiping
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Size {
+    /// Whole KiBs
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl Size {
+    /// Bytes of the size on a disk with `free_space` bytes, percentages are
+    /// rounded down to whole MiBs
+    pub fn resolve(self, free_space: u64) -> u64 {
+        match self {
+            Size::Bytes(bytes) => bytes,
+            Size::Percent(percent) => {
+                let bytes = (free_space as f64 * percent / 100.0) as u64;
+                bytes >> 20 << 20
+            }
+        }
+    }
+}
+
+impl FromStr for Size {
+    type Err = Error;
+
+    fn from_str(size: &str) -> Result<Self> {
+        let invalid = || {
+            format!(
+                r#""{}" isn't a valid size, e.g. 512M, 1.5G, 2GiB or 25%"#,
+                size
+            )
+        };
+
+        if let Some(percent) = size.strip_suffix('%') {
+            let percent: f64 = percent.trim().parse().with_context(invalid)?;
+            if !(percent > 0.0 && percent <= 100.0) {
+                bail!(r#""{}" isn't a percentage between 0 and 100"#, size);
+            }
+            return Ok(Size::Percent(percent));
+        }
+
+        let unit_at = size
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .with_context(invalid)?;
+        let (number, unit) = size.split_at(unit_at);
+        let unit = unit.strip_suffix("iB").unwrap_or(unit);
+        let shift = UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, shift)| *shift)
+            .with_context(invalid)?;
+        let number: f64 = number.parse().with_context(invalid)?;
+
+        let kibs = (number * (1u64 << (shift - 10)) as f64).ceil() as u64;
+        if kibs == 0 {
+            bail!(r#""{}" is empty"#, size);
+        }
+
+        Ok(Size::Bytes(kibs << 10))
+    }
+}
+
+/// Same form as parsed, in the largest unit the size is a multiple of, as
+/// sgdisk takes it
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Size::Bytes(bytes) => {
+                let (unit, shift) = UNITS
+                    .iter()
+                    .rev()
+                    .find(|(_, shift)| bytes % (1 << shift) == 0)
+                    .unwrap_or(&UNITS[0]);
+                write!(f, "{}{}", bytes >> shift, unit)
+            }
+            Size::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl TryFrom<String> for Size {
+    type Error = Error;
+
+    fn try_from(size: String) -> Result<Self> {
+        size.parse()
+    }
+}
+
+impl From<Size> for String {
+    fn from(size: Size) -> Self {
+        size.to_string()
+    }
+}
+
+/// `bytes` rounded for messages, e.g. "3.2G"
+pub fn human(bytes: u64) -> String {
+    let (unit, shift) = UNITS
+        .iter()
+        .rev()
+        .find(|(_, shift)| bytes >= 1 << shift)
+        .unwrap_or(&UNITS[0]);
+
+    format!("{:.1}{}", bytes as f64 / (1u64 << shift) as f64, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!("512M".parse::<Size>().unwrap(), Size::Bytes(512 * MIB));
+        assert_eq!("4G".parse::<Size>().unwrap(), Size::Bytes(4 * GIB));
+        assert_eq!("1.5G".parse::<Size>().unwrap(), Size::Bytes(1536 * MIB));
+        assert_eq!("2GiB".parse::<Size>().unwrap(), Size::Bytes(2 * GIB));
+        assert_eq!("300K".parse::<Size>().unwrap(), Size::Bytes(300 << 10));
+        assert_eq!("25%".parse::<Size>().unwrap(), Size::Percent(25.0));
+    }
+
+    #[test]
+    fn reject_invalid_sizes() {
+        for size in [
+            "", "512", "M", "4X", "4 G", "1.2.3G", "0M", "0%", "150%", "-1G",
+        ] {
+            assert!(size.parse::<Size>().is_err(), "{} was accepted", size);
+        }
+    }
+
+    #[test]
+    fn display_for_sgdisk() {
+        assert_eq!(Size::Bytes(512 * MIB).to_string(), "512M");
+        assert_eq!(Size::Bytes(4 * GIB).to_string(), "4G");
+        assert_eq!("1.5G".parse::<Size>().unwrap().to_string(), "1536M");
+        assert_eq!("2GiB".parse::<Size>().unwrap().to_string(), "2G");
+        assert_eq!(Size::Percent(12.5).to_string(), "12.5%");
+    }
+
+    #[test]
+    fn resolve_percentages() {
+        assert_eq!(Size::Percent(25.0).resolve(100 * GIB), 25 * GIB);
+        assert_eq!(Size::Percent(10.0).resolve(GIB + 1), 102 * MIB);
+        assert_eq!(Size::Bytes(4 * GIB).resolve(GIB), 4 * GIB);
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human(3 * GIB + 200 * MIB), "3.2G");
+        assert_eq!(human(512 * MIB), "512.0M");
+        assert_eq!(human(100), "0.1K");
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{
+    runner::{Cmd, CommandRunner, Executor, FileWriter},
+    sail::Layout,
+};
+use anyhow::{bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{fs, mem, path::Path};
+
+const STATE_PATH: &str = "sail_state.toml";
+
+/// Command undoing a change of `step`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Undo {
+    step: String,
+    argv: Vec<String>,
+}
+
+/// Progress of an installation, saved next to sail.toml after every step
+/// so an interrupted installation can be resumed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InstallState {
+    done: Vec<String>,
+    // TOML tables go after the plain values, and an empty array would be
+    // a plain value
+    layout: Layout,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    undo: Vec<Undo>,
+}
+
+impl InstallState {
+    /// State of a fresh installation
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            done: Vec::new(),
+            layout,
+            undo: Vec::new(),
+        }
+    }
+
+    /// Refuse to start over an unfinished installation, its state would be
+    /// overwritten
+    pub fn check_unfinished() -> Result<()> {
+        if Path::new(STATE_PATH).is_file() {
+            bail!(
+                "./{} found, a previous installation is unfinished\n\
+                 Continue it with `sail start --resume` or remove the file",
+                STATE_PATH
+            );
+        }
+
+        Ok(())
+    }
+
+    /// State of the installation to resume
+    pub fn load() -> Result<Self> {
+        if !Path::new(STATE_PATH).is_file() {
+            bail!("./{} not found, nothing to resume", STATE_PATH);
+        }
+
+        confy::load_path(STATE_PATH).context("Loading install state")
+    }
+
+    pub fn get_layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn is_done(&self, step: &str) -> bool {
+        self.done.iter().any(|done| done == step)
+    }
+
+    pub fn mark_done(&mut self, step: &str) {
+        self.done.push(step.to_owned());
+    }
+
+    pub fn save(&self) -> Result<()> {
+        confy::store_path(STATE_PATH, self).context("Saving install state")?;
+
+        Ok(())
+    }
+
+    /// Installation finished, nothing left to resume
+    pub fn remove(self) -> Result<()> {
+        if Path::new(STATE_PATH).is_file() {
+            fs::remove_file(STATE_PATH).context("Removing install state")?;
+        }
+
+        Ok(())
+    }
+
+    /// Run `undo` in reverse order, the failed commands are returned. A
+    /// failing undo command is reported but doesn't stop the others, the
+    /// change it undoes may not have happened.
+    fn run_undo(undo: Vec<Undo>, ex: &mut dyn Executor) -> Vec<Undo> {
+        let mut failed = Vec::new();
+        for undo in undo.into_iter().rev() {
+            if let Err(err) = ex.run(&Cmd::new(undo.argv.clone())) {
+                eprintln!("Undo failed, continuing: {:#}", err);
+                failed.push(undo);
+            }
+        }
+        failed.reverse();
+
+        failed
+    }
+
+    /// Undo the changes `step` made before failing with --keep-on-failure,
+    /// so it runs again from scratch. Returns whether there was anything to
+    /// undo.
+    pub fn undo_step(&mut self, step: &str, ex: &mut dyn Executor) -> Result<bool> {
+        let (partial, undo): (Vec<_>, _) = mem::take(&mut self.undo)
+            .into_iter()
+            .partition(|undo| undo.step == step);
+        self.undo = undo;
+        if partial.is_empty() {
+            return Ok(false);
+        }
+
+        ex.log(&format!("Undo the changes of the failed {}", step));
+        let failed = Self::run_undo(partial, ex);
+        if !failed.is_empty() {
+            self.undo.extend(failed);
+            // e.g. the pools aren't imported anymore after a reboot
+            bail!(
+                "Couldn't undo the changes of the failed {}, clean them up by hand \
+                 and remove their undo commands from ./{} before resuming",
+                step,
+                STATE_PATH
+            );
+        }
+
+        Ok(true)
+    }
+
+    /// Undo the changes of the installation, then forget about it. When an
+    /// undo command fails the state is kept with the failed ones, for the
+    /// user to clean up.
+    pub fn rollback(mut self, ex: &mut dyn Executor) -> Result<()> {
+        ex.log("Rollback installation");
+        self.undo = Self::run_undo(mem::take(&mut self.undo), ex);
+        if self.undo.is_empty() {
+            return self.remove();
+        }
+
+        // Nothing to resume anymore, only changes left behind
+        self.done.clear();
+        self.save()?;
+        eprintln!(
+            "\nRollback incomplete, the undo commands that failed are kept in ./{}\n\
+             Clean up by hand, then remove the file",
+            STATE_PATH
+        );
+
+        Ok(())
+    }
+}
+
+/// Executor recording the undo commands of setup step `step` into the
+/// install state
+pub struct Journal<'a> {
+    ex: &'a mut dyn Executor,
+    state: &'a mut InstallState,
+    step: &'a str,
+}
+
+impl<'a> Journal<'a> {
+    pub fn new(ex: &'a mut dyn Executor, state: &'a mut InstallState, step: &'a str) -> Self {
+        Self { ex, state, step }
+    }
+}
+
+impl CommandRunner for Journal<'_> {
+    fn run(&mut self, cmd: &Cmd) -> Result<()> {
+        self.ex.run(cmd)
+    }
+
+    fn output(&mut self, cmd: &Cmd) -> Result<String> {
+        self.ex.output(cmd)
+    }
+
+    fn query(&mut self, cmd: &Cmd) -> Result<String> {
+        self.ex.query(cmd)
+    }
+}
+
+impl FileWriter for Journal<'_> {
+    fn writeln_w(&mut self, content: &str, path: &str) -> Result<()> {
+        self.ex.writeln_w(content, path)
+    }
+
+    fn writeln_a(&mut self, content: &str, path: &str) -> Result<()> {
+        self.ex.writeln_a(content, path)
+    }
+}
+
+impl Executor for Journal<'_> {
+    fn log(&mut self, content: &str) {
+        self.ex.log(content)
+    }
+
+    fn push_undo(&mut self, cmd: Cmd) {
+        self.state.undo.push(Undo {
+            step: self.step.to_owned(),
+            argv: cmd.get_argv().to_vec(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runner::Recorder, sail::Sail, setup};
+
+    fn undo(step: &str, argv: &str) -> Undo {
+        Undo {
+            step: step.to_owned(),
+            argv: argv.split(' ').map(str::to_owned).collect(),
+        }
+    }
+
+    fn state() -> InstallState {
+        let mut state = InstallState::new(Sail::for_test("/dev/sda", 1).get_layout());
+        state.mark_done("partition_disk");
+        state.undo = vec![
+            undo("partition_disk", "partprobe"),
+            undo("partition_disk", "sgdisk -d1 /dev/sda"),
+            undo("format_disk", "zpool destroy -f bpool"),
+            undo("format_disk", "zpool destroy -f rpool"),
+        ];
+        state
+    }
+
+    #[test]
+    fn failed_undo_commands_are_kept() {
+        let mut rec = Recorder::default().with_failure(&["zpool", "destroy", "-f", "bpool"]);
+        let failed = InstallState::run_undo(state().undo, &mut rec);
+
+        // Every command runs, last change first
+        assert_eq!(
+            rec.argvs(),
+            [
+                vec!["zpool", "destroy", "-f", "rpool"],
+                vec!["zpool", "destroy", "-f", "bpool"],
+                vec!["sgdisk", "-d1", "/dev/sda"],
+                vec!["partprobe"]
+            ]
+        );
+        assert_eq!(failed, [undo("format_disk", "zpool destroy -f bpool")]);
+    }
+
+    #[test]
+    fn failed_step_is_undone_before_running_again() {
+        let mut state = state();
+        let mut rec = Recorder::default();
+        assert!(state.undo_step("format_disk", &mut rec).unwrap());
+        assert_eq!(
+            rec.argvs(),
+            [
+                vec!["zpool", "destroy", "-f", "rpool"],
+                vec!["zpool", "destroy", "-f", "bpool"]
+            ]
+        );
+        // The done step is only undone by a rollback
+        assert_eq!(state.undo.len(), 2);
+        assert!(!state.undo_step("format_disk", &mut rec).unwrap());
+
+        let mut state = self::state();
+        let mut rec = Recorder::default().with_failure(&["zpool"]);
+        assert!(state.undo_step("format_disk", &mut rec).is_err());
+        assert_eq!(state.undo.len(), 4);
+    }
+
+    #[test]
+    fn rollback_of_format_disk_unmounts_before_destroying() {
+        let sail = Sail::for_test("/dev/disk/by-id/ata-DISK", 1);
+        let mut state = InstallState::new(sail.get_layout());
+        let mut rec = Recorder::default();
+        setup::format_disk(
+            &sail,
+            &mut Journal::new(&mut rec, &mut state, "format_disk"),
+        )
+        .unwrap();
+
+        let mut rec = Recorder::default();
+        assert!(InstallState::run_undo(mem::take(&mut state.undo), &mut rec).is_empty());
+        assert_eq!(
+            rec.argvs(),
+            [
+                vec!["umount", "/mnt/boot/efi"],
+                vec!["umount", "/mnt/boot/efis/ata-DISK-part1"],
+                vec!["zfs", "unmount", "bpool/arch/BOOT/default"],
+                vec!["zpool", "destroy", "-f", "rpool"],
+                vec!["zpool", "destroy", "-f", "bpool"]
+            ]
+        );
+    }
+}
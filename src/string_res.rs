@@ -19,6 +19,14 @@ pub const ARCHZFS_REPO_C: &str = r"
 Include = /etc/pacman.d/mirrorlist-archzfs
 ";
 
+pub const LOCAL_PACMAN_C: &str = r"[options]
+Architecture = auto
+SigLevel = Required DatabaseRequired
+
+[sail]
+Server = file://{repo}
+";
+
 pub const PARU_INSTALL_I: &str = r"
 echo 'nobody ALL=(ALL) NOPASSWD: ALL' > /etc/sudoers.d/00_nobody
 su - nobody -s /bin/bash
@@ -66,11 +74,11 @@ jobs:
 - name: snapjob
   type: snap
   filesystems: {
-    "bpool/arch/BOOT": true,
-    "bpool/arch/BOOT/default": true,
-    "rpool/arch/DATA<": true,
-    "rpool/arch/ROOT": true,
-    "rpool/arch/ROOT/default": true,
+    "{bpool}/arch/BOOT": true,
+    "{bpool}/arch/BOOT/default": true,
+    "{rpool}/arch/DATA<": true,
+    "{rpool}/arch/ROOT": true,
+    "{rpool}/arch/ROOT/default": true,
   }
   snapshotting:
     type: periodic
@@ -87,8 +95,8 @@ jobs:
 "#;
 
 pub const GEN_INITRD_I: &str = r"
-zpool set cachefile=/etc/zfs/zpool.cache rpool
-zpool set cachefile=/etc/zfs/zpool.cache bpool
+zpool set cachefile=/etc/zfs/zpool.cache {rpool}
+zpool set cachefile=/etc/zfs/zpool.cache {bpool}
 mkinitcpio -P
 ";
 
@@ -167,13 +175,13 @@ WantedBy=multi-user.target
 
 pub const SERVICE_ENABLE_I: &str = r"
 systemctl enable NetworkManager
-systemctl enable zfs-scrub@rpool.timer
-systemctl enable zfs-scrub@bpool.timer
+systemctl enable zfs-scrub@{rpool}.timer
+systemctl enable zfs-scrub@{bpool}.timer
 ";
 
 pub const TRIM_ENABLE_I: &str = r"
-systemctl enable zfs-trim@rpool.timer
-systemctl enable zfs-trim@bpool.timer
+systemctl enable zfs-trim@{rpool}.timer
+systemctl enable zfs-trim@{bpool}.timer
 ";
 
 pub const ADDITIONAL_STORAGE_S: &str = r#"
@@ -181,6 +189,9 @@ my_user=UserName
 pool_name=tank0
 disk=/dev/disk/by-path/virtio-pci-0000:04:00.0-part1
 tmp_mpoint=/mnt/tmpmnt
+# "passphrase" to encrypt the pool, prompted now and at every boot
+encryption=off
+cipher=aes-256-gcm
 dsets_mpoint_pair=(
     "Downloads /home/${my_user}/Downloads"
     "dot_cache /home/${my_user}/.cache"
@@ -191,6 +202,34 @@ dsets_mpoint_pair=(
 set -e
 mkdir -p "$tmp_mpoint"
 
+encryption_opts=()
+mount_opts=x-systemd.automount,noauto,zfsutil,rw,xattr,posixacl
+if [ "$encryption" = passphrase ]; then
+    encryption_opts=(
+        -O encryption="$cipher"
+        -O keyformat=passphrase
+        -O keylocation=prompt
+    )
+    mount_opts+=,x-systemd.requires=zfs-load-key-${pool_name}.service
+
+    cat > /etc/systemd/system/zfs-load-key-${pool_name}.service <<EOF
+[Unit]
+Description=Load encryption key of ${pool_name}
+DefaultDependencies=no
+After=zfs-import.target
+Before=local-fs.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/usr/bin/bash -c 'systemd-ask-password "Passphrase of ${pool_name}:" | zfs load-key ${pool_name}'
+
+[Install]
+WantedBy=local-fs.target
+EOF
+    systemctl enable zfs-load-key-${pool_name}.service
+fi
+
 zpool create \
     -o ashift=12 \
     -o autotrim=on \
@@ -203,6 +242,7 @@ zpool create \
     -O relatime=on \
     -O xattr=sa \
     -O mountpoint=/ \
+    "${encryption_opts[@]}" \
     ${pool_name} \
     ${disk}
 
@@ -217,7 +257,7 @@ for pair in "${dsets_mpoint_pair[@]}"; do
 
     chown -R ${my_user}:${my_user} "$tmp_mpoint"/"$mpoint"
 
-    echo "${pool_name}/arch/DATA/default/$dset  $mpoint zfs x-systemd.automount,noauto,zfsutil,rw,xattr,posixacl   0 0" >> /etc/fstab
+    echo "${pool_name}/arch/DATA/default/$dset  $mpoint zfs $mount_opts   0 0" >> /etc/fstab
 done
 
 zpool set cachefile=/etc/zfs/zpool.cache "$pool_name"